@@ -1,8 +1,10 @@
 const AP_FALLBACK : &'static str = "ap.spotify.com:80";
+// Proxies tend to only allow tunnelling to port 443
+const AP_PROXY_FALLBACK : &'static str = "ap.spotify.com:443";
 const APRESOLVE_ENDPOINT : &'static str = "http://apresolve.spotify.com/";
 
 use std::str::FromStr;
-use futures::{Future, Stream};
+use futures::{future, Future, Stream};
 use hyper::{self, Uri, Client};
 use serde_json;
 use tokio_core::reactor::Handle;
@@ -44,10 +46,16 @@ pub fn apresolve(handle: &Handle) -> Box<Future<Item=String, Error=Error>> {
     Box::new(ap)
 }
 
-pub fn apresolve_or_fallback<E>(handle: &Handle)
+pub fn apresolve_or_fallback<E>(handle: &Handle, proxy: Option<&Uri>)
     -> Box<Future<Item=String, Error=E>>
     where E: 'static
 {
+    // apresolve would have to go through the proxy as well, which hyper's
+    // client can't do, so skip straight to the well-known AP.
+    if proxy.is_some() {
+        return Box::new(future::ok(AP_PROXY_FALLBACK.into()));
+    }
+
     let ap = apresolve(handle).or_else(|e| {
         warn!("Failed to resolve Access Point: {}", e.description());
         warn!("Using fallback \"{}\"", AP_FALLBACK);
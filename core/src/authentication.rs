@@ -10,11 +10,15 @@ use protobuf::ProtobufEnum;
 use rpassword;
 use serde;
 use serde_json;
+use std::error;
+use std::fmt;
 use std::io::{self, stderr, Read, Write};
 use std::fs::File;
 use std::path::Path;
 
 use protocol::authentication::AuthenticationType;
+use secrets::{self, SecretError};
+use util::write_private_file;
 
 #[derive(Debug, Clone)]
 #[derive(Serialize, Deserialize)]
@@ -39,7 +43,12 @@ impl Credentials {
         }
     }
 
-    pub fn with_blob(username: String, encrypted_blob: &str, device_id: &str) -> Credentials {
+    /// Decrypts the credentials blob a Spotify client sends to a discovery
+    /// `addUser` request. The blob comes from the network, so every step
+    /// fails with a `BlobError` instead of trusting its contents.
+    pub fn with_blob(username: String, encrypted_blob: &str, device_id: &str)
+        -> Result<Credentials, BlobError>
+    {
         fn read_u8<R: Read>(stream: &mut R) -> io::Result<u8> {
             let mut data = [0u8];
             try!(stream.read_exact(&mut data));
@@ -56,15 +65,27 @@ impl Credentials {
             Ok(lo & 0x7f | hi << 7)
         }
 
-        fn read_bytes<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
-            let length = try!(read_int(stream));
+        fn read_bytes(stream: &mut io::Cursor<&Vec<u8>>) -> io::Result<Vec<u8>> {
+            let length = try!(read_int(stream)) as u64;
+
+            // Don't allocate for lengths past the end of the blob
+            let remaining = stream.get_ref().len() as u64 - stream.position();
+            if length > remaining {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "field past end of blob"));
+            }
+
             let mut data = vec![0u8; length as usize];
             try!(stream.read_exact(&mut data));
 
             Ok(data)
         }
 
-        let encrypted_blob = base64::decode(encrypted_blob).unwrap();
+        let encrypted_blob = try!(base64::decode(encrypted_blob).map_err(|_| BlobError::InvalidBase64));
+
+        // ECB without padding only works on whole blocks
+        if encrypted_blob.is_empty() || encrypted_blob.len() % 0x10 != 0 {
+            return Err(BlobError::InvalidLength(encrypted_blob.len()));
+        }
 
         let secret = {
             let mut data = [0u8; 20];
@@ -92,10 +113,10 @@ impl Credentials {
             let mut cipher = aes::ecb_decryptor(aes::KeySize::KeySize192,
                                                 &key,
                                                 crypto::blockmodes::NoPadding);
-            cipher.decrypt(&mut crypto::buffer::RefReadBuffer::new(&encrypted_blob),
+            try!(cipher.decrypt(&mut crypto::buffer::RefReadBuffer::new(&encrypted_blob),
                            &mut crypto::buffer::RefWriteBuffer::new(&mut data),
                            true)
-                  .unwrap();
+                  .map_err(|_| BlobError::Decryption));
 
             let l = encrypted_blob.len();
             for i in 0..l - 0x10 {
@@ -106,40 +127,81 @@ impl Credentials {
         };
 
         let mut cursor = io::Cursor::new(&blob);
-        read_u8(&mut cursor).unwrap();
-        read_bytes(&mut cursor).unwrap();
-        read_u8(&mut cursor).unwrap();
-        let auth_type = read_int(&mut cursor).unwrap();
-        let auth_type = AuthenticationType::from_i32(auth_type as i32).unwrap();
-        read_u8(&mut cursor).unwrap();
-        let auth_data = read_bytes(&mut cursor).unwrap();;
-
-        Credentials {
+        try!(read_u8(&mut cursor).map_err(|_| BlobError::Truncated));
+        try!(read_bytes(&mut cursor).map_err(|_| BlobError::Truncated));
+        try!(read_u8(&mut cursor).map_err(|_| BlobError::Truncated));
+        let auth_type = try!(read_int(&mut cursor).map_err(|_| BlobError::Truncated));
+        let auth_type = try!(AuthenticationType::from_i32(auth_type as i32)
+            .ok_or(BlobError::UnknownAuthType(auth_type)));
+        try!(read_u8(&mut cursor).map_err(|_| BlobError::Truncated));
+        let auth_data = try!(read_bytes(&mut cursor).map_err(|_| BlobError::Truncated));
+
+        Ok(Credentials {
             username: username,
             auth_type: auth_type,
             auth_data: auth_data,
-        }
+        })
     }
 
-    pub fn from_reader<R: Read>(mut reader: R) -> Credentials {
+    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<Credentials> {
         let mut contents = String::new();
-        reader.read_to_string(&mut contents).unwrap();
+        try!(reader.read_to_string(&mut contents));
 
-        serde_json::from_str(&contents).unwrap()
+        serde_json::from_str(&contents)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
     }
 
-    pub fn from_file<P: AsRef<Path>>(path: P) -> Option<Credentials> {
-        File::open(path).ok().map(Credentials::from_reader)
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Credentials> {
+        File::open(path).and_then(Credentials::from_reader)
     }
 
-    pub fn save_to_writer<W: Write>(&self, writer: &mut W) {
-        let contents = serde_json::to_string(&self.clone()).unwrap();
-        writer.write_all(contents.as_bytes()).unwrap();
+    pub fn save_to_writer<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let contents = try!(serde_json::to_string(&self.clone())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)));
+        writer.write_all(contents.as_bytes())
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut data = Vec::new();
+        try!(self.save_to_writer(&mut data));
+        write_private_file(path.as_ref(), &data)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlobError {
+    InvalidBase64,
+    /// The encrypted blob is empty or not a whole number of AES blocks.
+    InvalidLength(usize),
+    Decryption,
+    /// A field runs past the end of the decrypted blob.
+    Truncated,
+    UnknownAuthType(u32),
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BlobError::InvalidBase64 => f.write_str("blob is not valid base64"),
+            BlobError::InvalidLength(len) => write!(f, "invalid blob length {}", len),
+            BlobError::Decryption => f.write_str("blob decryption failed"),
+            BlobError::Truncated => f.write_str("blob is truncated"),
+            BlobError::UnknownAuthType(auth_type) => {
+                write!(f, "unknown authentication type {}", auth_type)
+            }
+        }
     }
+}
 
-    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) {
-        let mut file = File::create(path).unwrap();
-        self.save_to_writer(&mut file)
+impl error::Error for BlobError {
+    fn description(&self) -> &str {
+        match *self {
+            BlobError::InvalidBase64 => "blob is not valid base64",
+            BlobError::InvalidLength(_) => "invalid blob length",
+            BlobError::Decryption => "blob decryption failed",
+            BlobError::Truncated => "blob is truncated",
+            BlobError::UnknownAuthType(_) => "unknown authentication type",
+        }
     }
 }
 
@@ -169,11 +231,18 @@ fn deserialize_base64<D>(de: D) -> Result<Vec<u8>, D::Error>
     base64::decode(&v).map_err(|e| serde::de::Error::custom(e.to_string()))
 }
 
+/// Picks the credentials to log in with. The password may refer to a file or
+/// command, see `secrets::resolve`.
 pub fn get_credentials(username: Option<String>, password: Option<String>,
                        cached_credentials: Option<Credentials>)
-    -> Option<Credentials>
+    -> Result<Option<Credentials>, SecretError>
 {
-    match (username, password, cached_credentials) {
+    let password = match password {
+        Some(password) => Some(try!(secrets::resolve(&password))),
+        None => None,
+    };
+
+    let credentials = match (username, password, cached_credentials) {
 
         (Some(username), Some(password), _)
             => Some(Credentials::with_password(username, password)),
@@ -192,5 +261,79 @@ pub fn get_credentials(username: Option<String>, password: Option<String>,
             => Some(credentials),
 
         (None, _, None) => None,
+    };
+
+    Ok(credentials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64;
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    const USERNAME: &'static str = "alice";
+    const DEVICE_ID: &'static str = "1e8f4c2a9b7d3e6f0a5c8b2d4e7f1a3c5b9d0e2f";
+
+    // Encrypted the way Spotify clients do, outside of this crate
+    const GOOD_BLOB: &'static str = "tutdqXTm3frr0qJtn2HpOF8LUEiZWK83xm1HDS7MPVaBX4DaTGeqr50ZoibMunjbAX68h7t\
+                                     vw4NUB2HpcreMWA==";
+    // The auth data claims 100 bytes, only 5 follow
+    const TRUNCATED_BLOB: &'static str = "B+eKFFgin7qBgy2usNNmig==";
+    // Auth type 99
+    const UNKNOWN_AUTH_TYPE_BLOB: &'static str = "vGVI7d108gmJ50amlmjybhWeOt9tiXt/wipjoGUGskmcFEJEb8hMUECjkl1afyNo\
+                                                  H8q5s9c+zhsjSeyybwaySw==";
+
+    fn with_blob(blob: &str) -> Result<Credentials, BlobError> {
+        Credentials::with_blob(USERNAME.to_owned(), blob, DEVICE_ID)
+    }
+
+    #[test]
+    fn decodes_blob() {
+        let credentials = with_blob(GOOD_BLOB).unwrap();
+        assert_eq!(credentials.username, USERNAME);
+        assert_eq!(credentials.auth_type, AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS);
+        assert_eq!(credentials.auth_data, b"reusable-credentials-for-alice-0123456789".to_vec());
+    }
+
+    #[test]
+    fn blob_is_tied_to_device() {
+        let credentials = Credentials::with_blob(USERNAME.to_owned(), GOOD_BLOB, "another device");
+        assert!(credentials.map(|c| c.auth_data != b"reusable-credentials-for-alice-0123456789".to_vec())
+                           .unwrap_or(true));
+    }
+
+    #[test]
+    fn rejects_malformed_blobs() {
+        assert_eq!(with_blob("not base64!").unwrap_err(), BlobError::InvalidBase64);
+        assert_eq!(with_blob("").unwrap_err(), BlobError::InvalidLength(0));
+        assert_eq!(with_blob(&base64::encode(&[0u8; 15])).unwrap_err(), BlobError::InvalidLength(15));
+        assert_eq!(with_blob(&base64::encode(&[0u8; 33])).unwrap_err(), BlobError::InvalidLength(33));
+        assert_eq!(with_blob(TRUNCATED_BLOB).unwrap_err(), BlobError::Truncated);
+        assert_eq!(with_blob(UNKNOWN_AUTH_TYPE_BLOB).unwrap_err(), BlobError::UnknownAuthType(99));
+
+        // Cut short, within a block or at a block boundary
+        let good = base64::decode(GOOD_BLOB).unwrap();
+        assert_eq!(with_blob(&base64::encode(&good[..40])).unwrap_err(), BlobError::InvalidLength(40));
+        assert_eq!(with_blob(&base64::encode(&good[..32])).unwrap_err(), BlobError::Truncated);
+    }
+
+    #[test]
+    fn never_panics_on_random_blobs() {
+        let mut rng = XorShiftRng::from_seed([0x193a6754, 0xa8a7d469, 0x97830e05, 0x113ba7bb]);
+
+        for _ in 0..2000 {
+            // Mostly whole blocks, to get past the length check
+            let len = if rng.gen() {
+                rng.gen_range(0, 8) * 0x10
+            } else {
+                rng.gen_range(0, 128)
+            };
+            let mut data = vec![0u8; len];
+            rng.fill_bytes(&mut data);
+
+            let _ = with_blob(&base64::encode(&data));
+            let _ = with_blob(&String::from_utf8_lossy(&data));
+        }
     }
 }
@@ -0,0 +1,167 @@
+use base64;
+use crypto;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::Sha256;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use rand;
+use serde_json;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+
+use util::rand_vec;
+
+const VERSION: u32 = 1;
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+// Tried in order, as on systemd and older dbus based systems
+const MACHINE_ID_PATHS: &'static [&'static str] = &["/etc/machine-id", "/var/lib/dbus/machine-id"];
+
+/// The secret cached files are encrypted with, either a passphrase from the
+/// config or the machine id.
+///
+/// Every file gets its own random salt, from which an AES-256-CTR key and an
+/// HMAC-SHA256 key are derived with PBKDF2.
+#[derive(Clone)]
+pub struct CacheKey {
+    secret: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    version: u32,
+    salt: String,
+    iv: String,
+    data: String,
+    mac: String,
+}
+
+#[derive(Debug)]
+pub enum EncryptionError {
+    Malformed,
+    UnsupportedVersion(u32),
+    /// The file was written with a different key, or has been tampered with.
+    BadMac,
+}
+
+impl CacheKey {
+    pub fn from_passphrase(passphrase: &str) -> CacheKey {
+        CacheKey {
+            secret: passphrase.as_bytes().to_owned(),
+        }
+    }
+
+    pub fn from_machine_id() -> Option<CacheKey> {
+        MACHINE_ID_PATHS.iter().filter_map(|path| {
+            let mut contents = String::new();
+            match File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+                Ok(_) if !contents.trim().is_empty() => Some(CacheKey {
+                    secret: contents.trim().as_bytes().to_owned(),
+                }),
+                _ => None,
+            }
+        }).next()
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        let salt: Vec<u8> = rand_vec(&mut rng, 16);
+        let iv: Vec<u8> = rand_vec(&mut rng, 16);
+
+        let (encryption_key, mac_key) = self.derive(&salt);
+
+        let mut data = vec![0u8; plaintext.len()];
+        crypto::aes::ctr(crypto::aes::KeySize::KeySize256, &encryption_key, &iv)
+            .process(plaintext, &mut data);
+
+        let mac = mac(&mac_key, &salt, &iv, &data);
+
+        let sealed = Sealed {
+            version: VERSION,
+            salt: base64::encode(&salt),
+            iv: base64::encode(&iv),
+            data: base64::encode(&data),
+            mac: base64::encode(&mac),
+        };
+
+        serde_json::to_vec(&sealed).unwrap()
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let sealed: Sealed = try!(serde_json::from_slice(sealed)
+            .map_err(|_| EncryptionError::Malformed));
+
+        if sealed.version != VERSION {
+            return Err(EncryptionError::UnsupportedVersion(sealed.version));
+        }
+
+        let decode = |value: &str| base64::decode(value).map_err(|_| EncryptionError::Malformed);
+        let salt = try!(decode(&sealed.salt));
+        let iv = try!(decode(&sealed.iv));
+        let data = try!(decode(&sealed.data));
+        let expected_mac = try!(decode(&sealed.mac));
+
+        if iv.len() != 16 {
+            return Err(EncryptionError::Malformed);
+        }
+
+        let (encryption_key, mac_key) = self.derive(&salt);
+
+        if !crypto::util::fixed_time_eq(&mac(&mac_key, &salt, &iv, &data), &expected_mac) {
+            return Err(EncryptionError::BadMac);
+        }
+
+        let mut plaintext = vec![0u8; data.len()];
+        crypto::aes::ctr(crypto::aes::KeySize::KeySize256, &encryption_key, &iv)
+            .process(&data, &mut plaintext);
+
+        Ok(plaintext)
+    }
+
+    fn derive(&self, salt: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut keys = [0u8; 64];
+        let mut mac = Hmac::new(Sha256::new(), &self.secret);
+        pbkdf2(&mut mac, salt, PBKDF2_ROUNDS, &mut keys);
+
+        (keys[..32].to_owned(), keys[32..].to_owned())
+    }
+}
+
+/// Whether `data` was written by `CacheKey::seal`, as opposed to a plaintext
+/// file from before encryption was enabled.
+pub fn is_sealed(data: &[u8]) -> bool {
+    serde_json::from_slice::<Sealed>(data).is_ok()
+}
+
+fn mac(key: &[u8], salt: &[u8], iv: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut h = Hmac::new(Sha256::new(), key);
+    h.input(salt);
+    h.input(iv);
+    h.input(data);
+    h.result().code().to_owned()
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncryptionError::Malformed => f.write_str("malformed encrypted file"),
+            EncryptionError::UnsupportedVersion(version) => {
+                write!(f, "unsupported encryption version {}", version)
+            }
+            EncryptionError::BadMac => f.write_str("wrong key or corrupted file"),
+        }
+    }
+}
+
+impl error::Error for EncryptionError {
+    fn description(&self) -> &str {
+        match *self {
+            EncryptionError::Malformed => "malformed encrypted file",
+            EncryptionError::UnsupportedVersion(_) => "unsupported encryption version",
+            EncryptionError::BadMac => "wrong key or corrupted file",
+        }
+    }
+}
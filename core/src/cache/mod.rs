@@ -1,25 +1,43 @@
-use std::path::PathBuf;
-use std::io::Read;
-use std::fs::File;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use std::path::{Path, PathBuf};
+use std::io::{self, Read};
+use std::fs::{self, File};
 
-use util::{FileId, mkdir_existing};
+use util::{FileId, mkdir_existing, write_private_file};
 use authentication::Credentials;
 
+mod encryption;
+pub use self::encryption::{CacheKey, EncryptionError};
+
 #[derive(Clone)]
 pub struct Cache {
     root: PathBuf,
     use_audio_cache: bool,
+    key: Option<CacheKey>,
 }
 
 impl Cache {
-    pub fn new(location: PathBuf, use_audio_cache: bool) -> Cache {
-        mkdir_existing(&location).unwrap();
-        mkdir_existing(&location.join("files")).unwrap();
+    pub fn new(location: PathBuf, use_audio_cache: bool) -> io::Result<Cache> {
+        try!(mkdir_existing(&location));
+        try!(mkdir_existing(&location.join("files")));
 
-        Cache {
+        Ok(Cache {
             root: location,
-            use_audio_cache: use_audio_cache
-        }
+            use_audio_cache: use_audio_cache,
+            key: None,
+        })
+    }
+
+    pub fn location(&self) -> &Path {
+        &self.root
+    }
+
+    /// Encrypts credentials and other secrets stored from now on. Plaintext
+    /// files written without a key are still read, and encrypted on the way.
+    pub fn encrypt_with(mut self, key: CacheKey) -> Cache {
+        self.key = Some(key);
+        self
     }
 }
 
@@ -28,14 +46,87 @@ impl Cache {
         self.root.join("credentials.json")
     }
 
-    pub fn credentials(&self) -> Option<Credentials> {
+    /// `Ok(None)` if no credentials have been cached yet.
+    pub fn credentials(&self) -> io::Result<Option<Credentials>> {
         let path = self.credentials_path();
-        Credentials::from_file(path)
+        match try!(self.load_secret(&path)) {
+            Some(data) => Credentials::from_reader(&data[..]).map(Some),
+            None => Ok(None),
+        }
     }
 
-    pub fn save_credentials(&self, cred: &Credentials) {
+    pub fn save_credentials(&self, cred: &Credentials) -> io::Result<()> {
         let path = self.credentials_path();
-        cred.save_to_file(&path);
+
+        let mut data = Vec::new();
+        try!(cred.save_to_writer(&mut data));
+        self.save_secret(&path, &data)
+    }
+
+    fn lastfm_session_path(&self, username: &str) -> PathBuf {
+        // Keeps arbitrary usernames out of the file name
+        let mut h = Sha1::new();
+        h.input_str(&username.to_lowercase());
+        self.root.join(format!("lastfm-session-{}", h.result_str()))
+    }
+
+    pub fn lastfm_session_key(&self, username: &str) -> io::Result<Option<String>> {
+        let path = self.lastfm_session_path(username);
+        match try!(self.load_secret(&path)) {
+            Some(data) => String::from_utf8(data)
+                .map(Some)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save_lastfm_session_key(&self, username: &str, session_key: &str) -> io::Result<()> {
+        let path = self.lastfm_session_path(username);
+        self.save_secret(&path, session_key.as_bytes())
+    }
+
+    /// Forgets a session key Last.fm no longer accepts.
+    pub fn remove_lastfm_session_key(&self, username: &str) -> io::Result<()> {
+        match fs::remove_file(self.lastfm_session_path(username)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn load_secret(&self, path: &Path) -> io::Result<Option<Vec<u8>>> {
+        let mut contents = Vec::new();
+        match File::open(path).and_then(|mut file| file.read_to_end(&mut contents)) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        if !encryption::is_sealed(&contents) {
+            if self.key.is_some() {
+                info!("Encrypting {}", path.display());
+                if let Err(err) = self.save_secret(path, &contents) {
+                    warn!("Cannot encrypt {}: {}", path.display(), err);
+                }
+            }
+            return Ok(Some(contents));
+        }
+
+        match self.key {
+            Some(ref key) => key.open(&contents)
+                .map(Some)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                       "encrypted, but no cache passphrase or machine id is available")),
+        }
+    }
+
+    fn save_secret(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let data = match self.key {
+            Some(ref key) => key.seal(data),
+            None => data.to_owned(),
+        };
+
+        write_private_file(path, &data)
     }
 }
 
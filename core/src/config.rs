@@ -1,3 +1,4 @@
+use hyper::Uri;
 use uuid::Uuid;
 use std::str::FromStr;
 use std::fmt;
@@ -8,6 +9,8 @@ use version;
 pub struct SessionConfig {
     pub user_agent: String,
     pub device_id: String,
+    /// HTTP proxy to tunnel the connection to Spotify through.
+    pub proxy: Option<Uri>,
 }
 
 impl Default for SessionConfig {
@@ -16,6 +19,7 @@ impl Default for SessionConfig {
         SessionConfig {
             user_agent: version::version_string(),
             device_id: device_id,
+            proxy: None,
         }
     }
 }
@@ -34,6 +38,24 @@ pub enum DeviceType {
     AudioDongle = 8,
 }
 
+impl DeviceType {
+    /// Maps the `kDeviceType` capability other devices advertise back to a `DeviceType`.
+    pub fn from_capability(value: i64) -> DeviceType {
+        use self::DeviceType::*;
+        match value {
+            1 => Computer,
+            2 => Tablet,
+            3 => Smartphone,
+            4 => Speaker,
+            5 => TV,
+            6 => AVR,
+            7 => STB,
+            8 => AudioDongle,
+            _ => Unknown,
+        }
+    }
+}
+
 impl FromStr for DeviceType {
     type Err = ();
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -75,8 +97,55 @@ impl Default for DeviceType {
     }
 }
 
+/// What the device does with playback commands sent to it, as it can't play
+/// anything itself.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum CommandPolicy {
+    /// Reports an error back to the sending client.
+    Reject,
+    /// Passes the command on to the device that was playing before, so
+    /// picking the scrobbler as output doesn't stop the music.
+    HandOff,
+}
+
+impl FromStr for CommandPolicy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(CommandPolicy::Reject),
+            "hand-off" | "handoff" => Ok(CommandPolicy::HandOff),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for CommandPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandPolicy::Reject => f.write_str("reject"),
+            CommandPolicy::HandOff => f.write_str("hand-off"),
+        }
+    }
+}
+
+impl Default for CommandPolicy {
+    fn default() -> CommandPolicy {
+        CommandPolicy::Reject
+    }
+}
+
 #[derive(Clone,Debug)]
 pub struct ConnectConfig {
     pub name: String,
     pub device_type: DeviceType,
+    /// Whether clients may transfer playback to the device. The scrobbler
+    /// never plays anything itself.
+    pub can_be_player: bool,
+    /// Keeps the device out of clients' device pickers, it still sees
+    /// everything played on the account.
+    pub hidden: bool,
+    /// Whether clients may rename the device. A new name lasts until the
+    /// device restarts.
+    pub supports_rename: bool,
+    pub command_policy: CommandPolicy,
 }
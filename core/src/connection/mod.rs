@@ -1,12 +1,14 @@
 mod codec;
 mod handshake;
+mod proxytunnel;
 
 pub use self::codec::APCodec;
 pub use self::handshake::handshake;
 
-use futures::{Future, Sink, Stream, BoxFuture};
+use futures::{future, Future, Sink, Stream, BoxFuture};
+use hyper::Uri;
 use std::io;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use tokio_core::net::TcpStream;
 use tokio_core::reactor::Handle;
 use tokio_core::io::Framed;
@@ -17,9 +19,38 @@ use version;
 
 pub type Transport = Framed<TcpStream, APCodec>;
 
-pub fn connect<A: ToSocketAddrs>(addr: A, handle: &Handle) -> BoxFuture<Transport, io::Error> {
-    let addr = addr.to_socket_addrs().unwrap().next().unwrap();
-    let socket = TcpStream::connect(&addr, handle);
+pub fn connect(addr: &str, handle: &Handle, proxy: Option<&Uri>) -> BoxFuture<Transport, io::Error> {
+    let socket = match proxy {
+        Some(proxy) => {
+            info!("Using proxy \"{}\"", proxy);
+
+            let proxy_addr = match proxy.host() {
+                Some(host) => resolve(&format!("{}:{}", host, proxy.port().unwrap_or(80))),
+                None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                           format!("proxy {} has no host", proxy))),
+            };
+            let proxy_addr = match proxy_addr {
+                Ok(proxy_addr) => proxy_addr,
+                Err(err) => return future::err(err).boxed(),
+            };
+
+            let mut parts = addr.rsplitn(2, ':');
+            let port = parts.next().and_then(|port| port.parse().ok()).unwrap_or(443);
+            let host = parts.next().unwrap_or(addr).to_owned();
+
+            TcpStream::connect(&proxy_addr, handle).and_then(move |socket| {
+                proxytunnel::connect(socket, &host, port)
+            }).boxed()
+        }
+        None => {
+            let addr = match resolve(addr) {
+                Ok(addr) => addr,
+                Err(err) => return future::err(err).boxed(),
+            };
+            TcpStream::connect(&addr, handle).boxed()
+        }
+    };
+
     let connection = socket.and_then(|socket| {
         handshake(socket)
     });
@@ -27,6 +58,13 @@ pub fn connect<A: ToSocketAddrs>(addr: A, handle: &Handle) -> BoxFuture<Transpor
     connection.boxed()
 }
 
+fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    let mut addrs = try!(addr.to_socket_addrs());
+    addrs.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("no address found for {}", addr))
+    })
+}
+
 pub fn authenticate(transport: Transport, credentials: Credentials, device_id: String)
     -> BoxFuture<(Transport, Credentials), io::Error>
 {
@@ -0,0 +1,67 @@
+use futures::{Async, Future, Poll, BoxFuture};
+use std::io::{self, Read};
+use tokio_core::io::write_all;
+use tokio_core::net::TcpStream;
+
+const MAX_RESPONSE_SIZE: usize = 8192;
+
+/// Asks an HTTP proxy to open a tunnel to `host:port` using `CONNECT`,
+/// resolving to the stream once the proxy has accepted.
+pub fn connect(connection: TcpStream, host: &str, port: u16) -> BoxFuture<TcpStream, io::Error> {
+    let request = format!("CONNECT {0}:{1} HTTP/1.1\r\n\
+                           Host: {0}:{1}\r\n\
+                           \r\n", host, port);
+
+    write_all(connection, request.into_bytes()).and_then(|(connection, _)| {
+        ProxyResponse {
+            connection: Some(connection),
+            response: Vec::new(),
+        }
+    }).boxed()
+}
+
+struct ProxyResponse {
+    connection: Option<TcpStream>,
+    response: Vec<u8>,
+}
+
+impl Future for ProxyResponse {
+    type Item = TcpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TcpStream, io::Error> {
+        // The AP won't talk before we do, so reading byte by byte can't
+        // swallow anything past the end of the proxy's response.
+        loop {
+            let mut byte = [0u8];
+            let read = match self.connection.as_mut().expect("poll after completion").read(&mut byte) {
+                Ok(read) => read,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(err) => return Err(err),
+            };
+
+            if read == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "proxy closed the connection"));
+            }
+
+            self.response.push(byte[0]);
+            if self.response.ends_with(b"\r\n\r\n") {
+                break;
+            } else if self.response.len() > MAX_RESPONSE_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "proxy response too large"));
+            }
+        }
+
+        let response = String::from_utf8_lossy(&self.response).into_owned();
+        let status = response.lines().next().unwrap_or("");
+
+        if status.split_whitespace().nth(1) == Some("200") {
+            Ok(Async::Ready(self.connection.take().unwrap()))
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               format!("proxy refused to connect: {}", status)))
+        }
+    }
+}
@@ -36,6 +36,8 @@ pub mod channel;
 pub mod config;
 pub mod diffie_hellman;
 pub mod mercury;
+pub mod metrics;
+pub mod secrets;
 pub mod session;
 pub mod util;
 pub mod version;
@@ -5,8 +5,10 @@ use protobuf;
 use protocol;
 use std::collections::HashMap;
 use std::mem;
+use std::time::Instant;
 use tokio_core::io::EasyBuf;
 
+use metrics::{self, Kind, Metric};
 use util::SeqGenerator;
 
 mod types;
@@ -27,8 +29,22 @@ pub struct MercuryPending {
     parts: Vec<Vec<u8>>,
     partial: Option<Vec<u8>>,
     callback: Option<oneshot::Sender<Result<MercuryResponse, MercuryError>>>,
+    /// What was asked and when, unless this is an event on a subscription.
+    request: Option<(MercuryMethod, Instant)>,
 }
 
+const REQUESTS: Metric = Metric {
+    name: "spotify_mercury_requests_total",
+    help: "Mercury requests answered, by method and status code.",
+    kind: Kind::Counter,
+};
+
+const REQUEST_DURATION: Metric = Metric {
+    name: "spotify_mercury_request_duration_seconds",
+    help: "Time taken to answer Mercury requests, by method.",
+    kind: Kind::Histogram(metrics::LATENCY_BUCKETS),
+};
+
 pub struct MercuryFuture<T>(oneshot::Receiver<Result<T, MercuryError>>);
 impl <T> Future for MercuryFuture<T> {
     type Item = T;
@@ -56,17 +72,17 @@ impl MercuryManager {
     {
         let (tx, rx) = oneshot::channel();
 
+        let seq = self.next_seq();
+        let cmd = req.method.command();
+        let data = req.encode(&seq);
+
         let pending = MercuryPending {
             parts: Vec::new(),
             partial: None,
             callback: Some(tx),
+            request: Some((req.method, Instant::now())),
         };
-
-        let seq = self.next_seq();
-        self.lock(|inner| inner.pending.insert(seq.clone(), pending));
-
-        let cmd = req.method.command();
-        let data = req.encode(&seq);
+        self.lock(|inner| inner.pending.insert(seq, pending));
 
         self.session().send_packet(cmd, data);
         MercuryFuture(rx)
@@ -98,6 +114,15 @@ impl MercuryManager {
         MercurySender::new(self.clone(), uri.into())
     }
 
+    /// Fails all pending requests and ends all subscriptions, for when the
+    /// connection behind the session has gone away.
+    pub fn shutdown(&self) {
+        self.lock(|inner| {
+            inner.pending.clear();
+            inner.subscriptions.clear();
+        });
+    }
+
     pub fn subscribe<T: Into<String>>(&self, uri: T)
         -> BoxFuture<mpsc::UnboundedReceiver<MercuryResponse>, MercuryError>
     {
@@ -152,6 +177,7 @@ impl MercuryManager {
                     parts: Vec::new(),
                     partial: None,
                     callback: None,
+                    request: None,
                 }
             }
             None => {
@@ -196,6 +222,12 @@ impl MercuryManager {
             payload: pending.parts,
         };
 
+        if let Some((ref method, ref started)) = pending.request {
+            let method = method.to_string();
+            REQUESTS.inc(&[("method", &method), ("status", &response.status_code.to_string())]);
+            REQUEST_DURATION.observe_duration(&[("method", &method)], started.elapsed());
+        }
+
         if response.status_code >= 400 {
             warn!("error {} for uri {}", response.status_code, &response.uri);
             if let Some(cb) = pending.callback {
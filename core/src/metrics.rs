@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Latency buckets, in seconds, for requests to Spotify and Last.fm.
+pub const LATENCY_BUCKETS: &'static [f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Histogram(&'static [f64]),
+}
+
+/// A metric as exported to Prometheus, declared as a constant next to the
+/// code it measures:
+///
+/// ```ignore
+/// const FRAMES: Metric = Metric {
+///     name: "spirc_frames_total",
+///     help: "SPIRC frames received, by type.",
+///     kind: Kind::Counter,
+/// };
+///
+/// FRAMES.inc(&[("type", "notify")]);
+/// ```
+///
+/// Series only show up once they have been recorded.
+#[derive(Debug, Clone, Copy)]
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    series: BTreeMap<Labels, Series>,
+}
+
+enum Series {
+    Value(f64),
+    Histogram { buckets: Vec<u64>, sum: f64, count: u64 },
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<BTreeMap<&'static str, Family>> = Mutex::new(BTreeMap::new());
+}
+
+impl Metric {
+    pub fn inc(&self, labels: &[(&'static str, &str)]) {
+        self.add(labels, 1.0);
+    }
+
+    /// Adds `delta` to a counter or gauge, which may be negative for gauges.
+    pub fn add(&self, labels: &[(&'static str, &str)], delta: f64) {
+        self.update(labels, |series| {
+            if let Series::Value(ref mut value) = *series {
+                *value += delta;
+            }
+        });
+    }
+
+    pub fn set(&self, labels: &[(&'static str, &str)], value: f64) {
+        self.update(labels, |series| {
+            if let Series::Value(ref mut current) = *series {
+                *current = value;
+            }
+        });
+    }
+
+    pub fn observe(&self, labels: &[(&'static str, &str)], value: f64) {
+        let bounds = match self.kind {
+            Kind::Histogram(bounds) => bounds,
+            _ => return,
+        };
+
+        self.update(labels, |series| {
+            if let Series::Histogram { ref mut buckets, ref mut sum, ref mut count } = *series {
+                for (bucket, bound) in buckets.iter_mut().zip(bounds) {
+                    if value <= *bound {
+                        *bucket += 1;
+                    }
+                }
+                *sum += value;
+                *count += 1;
+            }
+        });
+    }
+
+    pub fn observe_duration(&self, labels: &[(&'static str, &str)], duration: Duration) {
+        self.observe(labels, duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9);
+    }
+
+    fn update<F: FnOnce(&mut Series)>(&self, labels: &[(&'static str, &str)], f: F) {
+        let mut registry = REGISTRY.lock().unwrap();
+        let family = registry.entry(self.name).or_insert_with(|| Family {
+            help: self.help,
+            kind: self.kind,
+            series: BTreeMap::new(),
+        });
+
+        let labels = labels.iter().map(|&(name, value)| (name, value.to_owned())).collect();
+        let kind = self.kind;
+        let series = family.series.entry(labels).or_insert_with(|| match kind {
+            Kind::Histogram(bounds) => Series::Histogram {
+                buckets: vec![0; bounds.len()],
+                sum: 0.0,
+                count: 0,
+            },
+            _ => Series::Value(0.0),
+        });
+
+        f(series);
+    }
+}
+
+/// Every metric recorded so far, in the Prometheus text format.
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+
+    for (name, family) in registry.iter() {
+        let kind = match family.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram(_) => "histogram",
+        };
+        writeln!(out, "# HELP {} {}", name, family.help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+
+        for (labels, series) in &family.series {
+            match *series {
+                Series::Value(value) => {
+                    writeln!(out, "{}{} {}", name, format_labels(labels, None), value).unwrap();
+                }
+                Series::Histogram { ref buckets, sum, count } => {
+                    let bounds = match family.kind {
+                        Kind::Histogram(bounds) => bounds,
+                        _ => unreachable!(),
+                    };
+                    for (bucket, bound) in buckets.iter().zip(bounds) {
+                        writeln!(out, "{}_bucket{} {}", name,
+                                 format_labels(labels, Some(&bound.to_string())), bucket).unwrap();
+                    }
+                    writeln!(out, "{}_bucket{} {}", name, format_labels(labels, Some("+Inf")), count).unwrap();
+                    writeln!(out, "{}_sum{} {}", name, format_labels(labels, None), sum).unwrap();
+                    writeln!(out, "{}_count{} {}", name, format_labels(labels, None), count).unwrap();
+                }
+            }
+        }
+    }
+
+    out
+}
+
+fn format_labels(labels: &[(&'static str, String)], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels.iter()
+        .map(|&(name, ref value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The registry is shared by every test, so each uses metrics of its own
+    fn lines(name: &str) -> Vec<String> {
+        render().lines()
+            .filter(|line| line.starts_with(name) || line.contains(&format!(" {} ", name)))
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn renders_help_and_type() {
+        const REQUESTS: Metric = Metric {
+            name: "test_help_requests_total",
+            help: "Requests, by method.",
+            kind: Kind::Counter,
+        };
+        const TEMPERATURE: Metric = Metric {
+            name: "test_help_temperature",
+            help: "Temperature.",
+            kind: Kind::Gauge,
+        };
+
+        assert!(lines(REQUESTS.name).is_empty());
+
+        REQUESTS.inc(&[("method", "GET")]);
+        REQUESTS.inc(&[("method", "GET")]);
+        REQUESTS.inc(&[("method", "POST")]);
+        TEMPERATURE.set(&[], 21.5);
+
+        assert_eq!(lines(REQUESTS.name), vec![
+            "# HELP test_help_requests_total Requests, by method.",
+            "# TYPE test_help_requests_total counter",
+            "test_help_requests_total{method=\"GET\"} 2",
+            "test_help_requests_total{method=\"POST\"} 1",
+        ]);
+        assert_eq!(lines(TEMPERATURE.name), vec![
+            "# HELP test_help_temperature Temperature.",
+            "# TYPE test_help_temperature gauge",
+            "test_help_temperature 21.5",
+        ]);
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        const ERRORS: Metric = Metric {
+            name: "test_escape_errors_total",
+            help: "Errors.",
+            kind: Kind::Counter,
+        };
+
+        ERRORS.inc(&[("reason", "C:\\path \"quoted\"\nnext line"), ("sink", "lastfm")]);
+
+        assert_eq!(lines(ERRORS.name)[2],
+                   "test_escape_errors_total{reason=\"C:\\\\path \\\"quoted\\\"\\nnext line\",sink=\"lastfm\"} 1");
+    }
+
+    #[test]
+    fn renders_cumulative_histogram() {
+        const LATENCY: Metric = Metric {
+            name: "test_histogram_latency_seconds",
+            help: "Latency.",
+            kind: Kind::Histogram(&[0.25, 1.0, 4.0]),
+        };
+
+        for &value in &[0.125, 0.25, 0.5, 2.0, 32.0] {
+            LATENCY.observe(&[("method", "GET")], value);
+        }
+        LATENCY.observe_duration(&[("method", "GET")], Duration::from_millis(250));
+
+        assert_eq!(lines(LATENCY.name), vec![
+            "# HELP test_histogram_latency_seconds Latency.",
+            "# TYPE test_histogram_latency_seconds histogram",
+            "test_histogram_latency_seconds_bucket{method=\"GET\",le=\"0.25\"} 3",
+            "test_histogram_latency_seconds_bucket{method=\"GET\",le=\"1\"} 4",
+            "test_histogram_latency_seconds_bucket{method=\"GET\",le=\"4\"} 5",
+            "test_histogram_latency_seconds_bucket{method=\"GET\",le=\"+Inf\"} 6",
+            "test_histogram_latency_seconds_sum{method=\"GET\"} 35.125",
+            "test_histogram_latency_seconds_count{method=\"GET\"} 6",
+        ]);
+    }
+
+    #[test]
+    fn ignores_updates_of_the_wrong_kind() {
+        const LATENCY: Metric = Metric {
+            name: "test_kind_latency_seconds",
+            help: "Latency.",
+            kind: Kind::Histogram(&[1.0]),
+        };
+        const COUNT: Metric = Metric {
+            name: "test_kind_count_total",
+            help: "Count.",
+            kind: Kind::Counter,
+        };
+
+        LATENCY.observe(&[], 0.5);
+        LATENCY.inc(&[]);
+        COUNT.observe(&[], 0.5);
+
+        assert_eq!(lines(LATENCY.name), vec![
+            "# HELP test_kind_latency_seconds Latency.",
+            "# TYPE test_kind_latency_seconds histogram",
+            "test_kind_latency_seconds_bucket{le=\"1\"} 1",
+            "test_kind_latency_seconds_bucket{le=\"+Inf\"} 1",
+            "test_kind_latency_seconds_sum 0.5",
+            "test_kind_latency_seconds_count 1",
+        ]);
+        assert!(lines(COUNT.name).is_empty());
+    }
+
+    #[test]
+    fn gauge_goes_negative_and_back() {
+        const PENDING: Metric = Metric {
+            name: "test_gauge_pending",
+            help: "Pending.",
+            kind: Kind::Gauge,
+        };
+
+        PENDING.add(&[], -1.0);
+        assert_eq!(lines(PENDING.name)[2], "test_gauge_pending -1");
+
+        PENDING.add(&[], 1.0);
+        PENDING.add(&[], 1.0);
+        assert_eq!(lines(PENDING.name)[2], "test_gauge_pending 1");
+
+        PENDING.add(&[], -1.0);
+        assert_eq!(lines(PENDING.name)[2], "test_gauge_pending 0");
+    }
+}
@@ -0,0 +1,81 @@
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::process::Command;
+
+/// Resolves a secret given in the config or on the command line.
+///
+/// `file:<path>` reads the secret from a file, as mounted by Docker or
+/// Kubernetes secrets. `cmd:<command>` runs the command through the shell and
+/// uses its output, e.g. `cmd:pass show lastfm`. Anything else is taken
+/// literally. A single trailing newline is stripped from files and output.
+pub fn resolve(value: &str) -> Result<String, SecretError> {
+    if value.starts_with("file:") {
+        let path = &value["file:".len()..];
+        read_file(path)
+            .map(trim_newline)
+            .map_err(|err| SecretError::File(path.to_owned(), err))
+    } else if value.starts_with("cmd:") {
+        let command = &value["cmd:".len()..];
+        run_command(command).map(trim_newline)
+    } else {
+        Ok(value.to_owned())
+    }
+}
+
+#[derive(Debug)]
+pub enum SecretError {
+    File(String, io::Error),
+    Command(String, String),
+}
+
+fn read_file(path: &str) -> io::Result<String> {
+    let mut contents = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut contents));
+    Ok(contents)
+}
+
+fn run_command(command: &str) -> Result<String, SecretError> {
+    let error = |msg: String| SecretError::Command(command.to_owned(), msg);
+
+    let output = try!(Command::new("sh").arg("-c").arg(command).output()
+        .map_err(|err| error(err.to_string())));
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(error(format!("{} ({})", output.status, stderr.trim())));
+    }
+
+    String::from_utf8(output.stdout).map_err(|_| error("output is not valid UTF-8".to_owned()))
+}
+
+fn trim_newline(mut value: String) -> String {
+    if value.ends_with('\n') {
+        value.pop();
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    }
+    value
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SecretError::File(ref path, ref err) => write!(f, "cannot read {}: {}", path, err),
+            SecretError::Command(ref command, ref err) => {
+                write!(f, "command {:?} failed: {}", command, err)
+            }
+        }
+    }
+}
+
+impl error::Error for SecretError {
+    fn description(&self) -> &str {
+        match *self {
+            SecretError::File(..) => "cannot read secret file",
+            SecretError::Command(..) => "secret command failed",
+        }
+    }
+}
@@ -6,7 +6,7 @@ use std::io;
 use std::sync::{RwLock, Arc, Weak};
 use tokio_core::io::EasyBuf;
 use tokio_core::reactor::{Handle, Remote};
-use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 
 use apresolve::apresolve_or_fallback;
 use authentication::Credentials;
@@ -14,6 +14,7 @@ use cache::Cache;
 use component::Lazy;
 use connection;
 use config::SessionConfig;
+use metrics::{Kind, Metric};
 
 use audio_key::AudioKeyManager;
 use channel::ChannelManager;
@@ -38,10 +39,23 @@ pub struct SessionInternal {
     handle: Remote,
 
     session_id: usize,
+    invalid: AtomicBool,
 }
 
 static SESSION_COUNTER : AtomicUsize = ATOMIC_USIZE_INIT;
 
+const CONNECTS: Metric = Metric {
+    name: "spotify_session_connects_total",
+    help: "Attempts to connect to Spotify, by result.",
+    kind: Kind::Counter,
+};
+
+const DISCONNECTS: Metric = Metric {
+    name: "spotify_session_disconnects_total",
+    help: "Connections to Spotify which ended, by reason.",
+    kind: Kind::Counter,
+};
+
 #[derive(Clone)]
 pub struct Session(pub Arc<SessionInternal>);
 
@@ -56,13 +70,14 @@ impl Session {
                    cache: Option<Cache>, handle: Handle)
         -> Box<Future<Item=Session, Error=io::Error>>
     {
-        let access_point = apresolve_or_fallback::<io::Error>(&handle);
+        let access_point = apresolve_or_fallback::<io::Error>(&handle, config.proxy.as_ref());
 
 
         let handle_ = handle.clone();
+        let proxy = config.proxy.clone();
         let connection = access_point.and_then(move |addr| {
             info!("Connecting to AP \"{}\"", addr);
-            connection::connect::<&str>(&addr, &handle_)
+            connection::connect(&addr, &handle_, proxy.as_ref())
         });
 
         let device_id = config.device_id.clone();
@@ -73,18 +88,40 @@ impl Session {
         let result = authentication.map(move |(transport, reusable_credentials)| {
             info!("Authenticated as \"{}\" !", reusable_credentials.username);
             if let Some(ref cache) = cache {
-                cache.save_credentials(&reusable_credentials);
+                if let Err(err) = cache.save_credentials(&reusable_credentials) {
+                    warn!("Cannot cache credentials: {}", err);
+                }
             }
 
             let (session, task) = Session::create(
                 &handle, transport, config, cache, reusable_credentials.username.clone()
             );
 
-            handle.spawn(task.map_err(|e| panic!(e)));
+            let weak = session.weak();
+            handle.spawn(task.then(move |result| {
+                let reason = match result {
+                    Ok(()) => "closed",
+                    Err(err) => {
+                        error!("Connection to Spotify failed: {}", err);
+                        "error"
+                    }
+                };
+                DISCONNECTS.inc(&[("reason", reason)]);
+
+                // Wakes up anyone waiting on the session so they can notice
+                if let Some(session) = weak.try_upgrade() {
+                    session.shutdown();
+                }
+
+                Ok(())
+            }));
 
             session
+        }).then(|result| {
+            CONNECTS.inc(&[("result", if result.is_ok() { "ok" } else { "error" })]);
+            result
         });
-        
+
         Box::new(result)
     }
 
@@ -117,6 +154,7 @@ impl Session {
             handle: handle.remote().clone(),
 
             session_id: session_id,
+            invalid: AtomicBool::new(false),
         }));
 
         let sender_task = sender_rx
@@ -124,8 +162,11 @@ impl Session {
             .forward(sink).map(|_| ());
         let receiver_task = DispatchTask(stream, session.weak());
 
-        let task = (receiver_task, sender_task).into_future()
-            .map(|((), ())| ()).boxed();
+        // Whichever half stops first takes the connection down with it
+        let task = receiver_task.select(sender_task)
+            .map(|((), _)| ())
+            .map_err(|(err, _)| err)
+            .boxed();
 
         (session, task)
     }
@@ -177,7 +218,20 @@ impl Session {
     }
 
     pub fn send_packet(&self, cmd: u8, data: Vec<u8>) {
-        self.0.tx_connection.send((cmd, data)).unwrap();
+        if self.0.tx_connection.send((cmd, data)).is_err() {
+            debug!("Session[{}] dropping packet {:#x}, connection closed", self.0.session_id, cmd);
+        }
+    }
+
+    /// Marks the session as unusable once its connection is gone.
+    pub fn shutdown(&self) {
+        debug!("Session[{}] shutting down", self.0.session_id);
+        self.0.invalid.store(true, Ordering::SeqCst);
+        self.mercury().shutdown();
+    }
+
+    pub fn is_invalid(&self) -> bool {
+        self.0.invalid.load(Ordering::SeqCst)
     }
 
     pub fn cache(&self) -> Option<&Arc<Cache>> {
@@ -246,7 +300,13 @@ impl <S> Future for DispatchTask<S>
         };
 
         loop {
-            let (cmd, data) = try_ready!(self.0.poll()).expect("connection closed");
+            let (cmd, data) = match try_ready!(self.0.poll()) {
+                Some(packet) => packet,
+                None => {
+                    warn!("Connection to Spotify closed");
+                    return Ok(Async::Ready(()));
+                }
+            };
             session.dispatch(cmd, data);
         }
     }
@@ -2,7 +2,7 @@ use num_bigint::BigUint;
 use num_traits::{Zero, One};
 use num_integer::Integer;
 use rand::{Rng, Rand};
-use std::io;
+use std::io::{self, Write};
 use std::mem;
 use std::ops::{Mul, Rem, Shr};
 use std::fs;
@@ -12,10 +12,12 @@ use std::time::{UNIX_EPOCH, SystemTime};
 
 mod int128;
 mod spotify_id;
+mod spotify_uri;
 mod subfile;
 
 pub use util::int128::u128;
-pub use util::spotify_id::{SpotifyId, FileId};
+pub use util::spotify_id::{SpotifyId, SpotifyIdError, FileId};
+pub use util::spotify_uri::{SpotifyUri, SpotifyUriError};
 pub use util::subfile::Subfile;
 
 pub fn rand_vec<G: Rng, R: Rand>(rng: &mut G, size: usize) -> Vec<R> {
@@ -40,6 +42,48 @@ pub fn mkdir_existing(path: &Path) -> io::Result<()> {
     })
 }
 
+/// Creates or truncates a file only the current user can read and write.
+#[cfg(unix)]
+pub fn create_private_file(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let file = try!(fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path));
+
+    // The mode only applies to newly created files
+    try!(file.set_permissions(fs::Permissions::from_mode(0o600)));
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+pub fn create_private_file(path: &Path) -> io::Result<fs::File> {
+    fs::File::create(path)
+}
+
+/// Replaces the contents of a private file through a temporary file in the
+/// same directory, so a crash half way leaves either the old or the new
+/// contents behind.
+pub fn write_private_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp_name = path.file_name().map(|name| name.to_owned()).unwrap_or_default();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = create_private_file(&temp_path).and_then(|mut file| {
+        try!(file.write_all(data));
+        file.sync_all()
+    }).and_then(|()| fs::rename(&temp_path, path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
 pub fn run_program(program: &str) {
     info!("Running {}", program);
     let mut v: Vec<&str> = program.split_whitespace().collect();
@@ -1,4 +1,5 @@
 use std;
+use std::error;
 use std::fmt;
 use util::u128;
 use byteorder::{BigEndian, ByteOrder};
@@ -7,6 +8,13 @@ use std::ascii::AsciiExt;
 #[derive(Debug,Copy,Clone,PartialEq,Eq,Hash)]
 pub struct SpotifyId(u128);
 
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum SpotifyIdError {
+    InvalidLength(usize),
+    InvalidDigit(char),
+    Overflow,
+}
+
 const BASE62_DIGITS: &'static [u8] =
     b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
 const BASE16_DIGITS: &'static [u8] = b"0123456789abcdef";
@@ -40,6 +48,40 @@ impl SpotifyId {
         SpotifyId(n)
     }
 
+    pub fn try_from_base16(id: &str) -> Result<SpotifyId, SpotifyIdError> {
+        SpotifyId::try_from_radix(id, BASE16_DIGITS, 32)
+    }
+
+    pub fn try_from_base62(id: &str) -> Result<SpotifyId, SpotifyIdError> {
+        SpotifyId::try_from_radix(id, BASE62_DIGITS, 22)
+    }
+
+    fn try_from_radix(id: &str, digits: &[u8], length: usize) -> Result<SpotifyId, SpotifyIdError> {
+        if id.len() != length {
+            return Err(SpotifyIdError::InvalidLength(id.len()));
+        }
+
+        let mut limbs = [0u64; 4];
+        for c in id.chars() {
+            let d = match digits.iter().position(|&e| e as char == c) {
+                Some(d) => d as u64,
+                None => return Err(SpotifyIdError::InvalidDigit(c)),
+            };
+
+            try!(mul_add(&mut limbs, digits.len() as u64, d));
+        }
+
+        Ok(SpotifyId(from_limbs(limbs)))
+    }
+
+    pub fn try_from_raw(data: &[u8]) -> Result<SpotifyId, SpotifyIdError> {
+        if data.len() != 16 {
+            return Err(SpotifyIdError::InvalidLength(data.len()));
+        }
+
+        Ok(SpotifyId::from_raw(data))
+    }
+
     pub fn from_raw(data: &[u8]) -> SpotifyId {
         assert_eq!(data.len(), 16);
 
@@ -64,6 +106,18 @@ impl SpotifyId {
         std::str::from_utf8(&data).unwrap().to_owned()
     }
 
+    pub fn to_base62(&self) -> String {
+        let &SpotifyId(ref n) = self;
+        let mut limbs = to_limbs(*n);
+
+        let mut data = [0u8; 22];
+        for i in 0..22 {
+            data[21 - i] = BASE62_DIGITS[div_rem(&mut limbs, 62) as usize];
+        }
+
+        std::str::from_utf8(&data).unwrap().to_owned()
+    }
+
     pub fn to_raw(&self) -> [u8; 16] {
         let &SpotifyId(ref n) = self;
         let (high, low) = n.parts();
@@ -77,6 +131,63 @@ impl SpotifyId {
     }
 }
 
+// Arithmetic on 32-bit limbs stored in u64s, most significant first. Unlike
+// `u128`'s operators these never panic, so they can be used on untrusted input.
+fn to_limbs(n: u128) -> [u64; 4] {
+    let (high, low) = n.parts();
+    [high >> 32, high & 0xFFFFFFFF, low >> 32, low & 0xFFFFFFFF]
+}
+
+fn from_limbs(limbs: [u64; 4]) -> u128 {
+    u128::from_parts(limbs[0] << 32 | limbs[1], limbs[2] << 32 | limbs[3])
+}
+
+fn mul_add(limbs: &mut [u64; 4], radix: u64, digit: u64) -> Result<(), SpotifyIdError> {
+    let mut carry = digit;
+    for limb in limbs.iter_mut().rev() {
+        let value = *limb * radix + carry;
+        *limb = value & 0xFFFFFFFF;
+        carry = value >> 32;
+    }
+
+    if carry != 0 {
+        Err(SpotifyIdError::Overflow)
+    } else {
+        Ok(())
+    }
+}
+
+fn div_rem(limbs: &mut [u64; 4], divisor: u64) -> u64 {
+    let mut rem = 0;
+    for limb in limbs.iter_mut() {
+        let value = rem << 32 | *limb;
+        *limb = value / divisor;
+        rem = value % divisor;
+    }
+
+    rem
+}
+
+impl fmt::Display for SpotifyIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SpotifyIdError::InvalidLength(len) => write!(f, "invalid id length {}", len),
+            SpotifyIdError::InvalidDigit(c) => write!(f, "invalid character {:?} in id", c),
+            SpotifyIdError::Overflow => f.write_str("id does not fit in 128 bits"),
+        }
+    }
+}
+
+impl error::Error for SpotifyIdError {
+    fn description(&self) -> &str {
+        match *self {
+            SpotifyIdError::InvalidLength(_) => "invalid id length",
+            SpotifyIdError::InvalidDigit(_) => "invalid character in id",
+            SpotifyIdError::Overflow => "id does not fit in 128 bits",
+        }
+    }
+}
+
 #[derive(Copy,Clone,PartialEq,Eq,PartialOrd,Ord,Hash)]
 pub struct FileId(pub [u8; 20]);
 
@@ -101,3 +212,60 @@ impl fmt::Display for FileId {
         f.write_str(&self.to_base16())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE62: &'static str = "4uLU6hMCjMI75M1A2tKUQC";
+    const BASE16: &'static str = "93bc414a606747b2b612491ef83d5a3e";
+
+    #[test]
+    fn converts_between_encodings() {
+        let id = SpotifyId::try_from_base62(BASE62).unwrap();
+        assert_eq!(SpotifyId::try_from_base16(BASE16), Ok(id));
+        assert_eq!(SpotifyId::try_from_raw(&id.to_raw()), Ok(id));
+        assert_eq!(id.to_base62(), BASE62);
+        assert_eq!(id.to_base16(), BASE16);
+    }
+
+    #[test]
+    fn rejects_base16_ids() {
+        assert_eq!(SpotifyId::try_from_base16(&BASE16[1..]), Err(SpotifyIdError::InvalidLength(31)));
+        assert_eq!(SpotifyId::try_from_base16(&format!("{}0", BASE16)),
+                   Err(SpotifyIdError::InvalidLength(33)));
+        assert_eq!(SpotifyId::try_from_base16(""), Err(SpotifyIdError::InvalidLength(0)));
+        assert_eq!(SpotifyId::try_from_base16("93bc414a606747b2b612491ef83d5a3E"),
+                   Err(SpotifyIdError::InvalidDigit('E')));
+        assert_eq!(SpotifyId::try_from_base16("93bc414a606747b2b612491ef83d5ag0"),
+                   Err(SpotifyIdError::InvalidDigit('g')));
+        // Two bytes in UTF-8, so the length alone doesn't catch it
+        assert_eq!(SpotifyId::try_from_base16("93bc414a606747b2b612491ef83d5a\u{e9}"),
+                   Err(SpotifyIdError::InvalidDigit('\u{e9}')));
+    }
+
+    #[test]
+    fn rejects_raw_ids() {
+        assert_eq!(SpotifyId::try_from_raw(&[0; 15]), Err(SpotifyIdError::InvalidLength(15)));
+        assert_eq!(SpotifyId::try_from_raw(&[0; 17]), Err(SpotifyIdError::InvalidLength(17)));
+        assert_eq!(SpotifyId::try_from_raw(&[]), Err(SpotifyIdError::InvalidLength(0)));
+    }
+
+    #[test]
+    fn rejects_base62_ids() {
+        assert_eq!(SpotifyId::try_from_base62(&BASE62[1..]), Err(SpotifyIdError::InvalidLength(21)));
+        assert_eq!(SpotifyId::try_from_base62("4uLU6hMCjMI75M1A2tKUQ-"),
+                   Err(SpotifyIdError::InvalidDigit('-')));
+    }
+
+    #[test]
+    fn rejects_base62_ids_above_128_bits() {
+        let max = SpotifyId::try_from_raw(&[0xFF; 16]).unwrap();
+        assert_eq!(max.to_base62(), "7N42dgm5tFLK9N8MT7fHC7");
+        assert_eq!(SpotifyId::try_from_base62("7N42dgm5tFLK9N8MT7fHC7"), Ok(max));
+
+        // 2^128, which would wrap around to 0
+        assert_eq!(SpotifyId::try_from_base62("7N42dgm5tFLK9N8MT7fHC8"), Err(SpotifyIdError::Overflow));
+        assert_eq!(SpotifyId::try_from_base62("ZZZZZZZZZZZZZZZZZZZZZZ"), Err(SpotifyIdError::Overflow));
+    }
+}
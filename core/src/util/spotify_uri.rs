@@ -0,0 +1,343 @@
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+use util::spotify_id::{SpotifyId, SpotifyIdError};
+
+const URL_HOSTS: &'static [&'static str] = &["open.spotify.com", "play.spotify.com"];
+
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub enum SpotifyUri {
+    Track(SpotifyId),
+    Album(SpotifyId),
+    Artist(SpotifyId),
+    Episode(SpotifyId),
+    Show(SpotifyId),
+    Audiobook(SpotifyId),
+    Chapter(SpotifyId),
+    Ad(SpotifyId),
+    Playlist {
+        user: Option<String>,
+        id: SpotifyId,
+    },
+    /// A file from the desktop client's local library, which has no Spotify id.
+    Local {
+        artist: String,
+        album: String,
+        title: String,
+        duration: Option<u32>,
+    },
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum SpotifyUriError {
+    InvalidFormat,
+    UnsupportedType(String),
+    InvalidId(SpotifyIdError),
+}
+
+impl SpotifyUri {
+    /// Parses either a `spotify:` URI or an `open.spotify.com` share link.
+    pub fn parse(uri: &str) -> Result<SpotifyUri, SpotifyUriError> {
+        if uri.starts_with("spotify:") {
+            SpotifyUri::from_uri(uri)
+        } else {
+            SpotifyUri::from_url(uri)
+        }
+    }
+
+    pub fn from_uri(uri: &str) -> Result<SpotifyUri, SpotifyUriError> {
+        let parts = uri.split(':').collect::<Vec<_>>();
+        match parts.first() {
+            Some(&"spotify") => SpotifyUri::from_parts(&parts[1..]),
+            _ => Err(SpotifyUriError::InvalidFormat),
+        }
+    }
+
+    pub fn from_url(url: &str) -> Result<SpotifyUri, SpotifyUriError> {
+        let url = url.trim_left_matches("https://").trim_left_matches("http://");
+        let url = url.split(|c: char| c == '?' || c == '#').next().unwrap();
+
+        let mut parts = url.split('/').collect::<Vec<_>>();
+        if !URL_HOSTS.iter().any(|host| *host == parts[0]) {
+            return Err(SpotifyUriError::InvalidFormat);
+        }
+        parts.remove(0);
+
+        let start = parts.iter().position(|part| !part.is_empty()).unwrap_or(parts.len());
+        parts.drain(..start);
+
+        // Localised share links carry a locale segment, e.g. `/intl-de/track/...`
+        if parts.first().map_or(false, |part| part.starts_with("intl-")) {
+            parts.remove(0);
+        }
+
+        // Empty segments from doubled or trailing slashes are dropped, except
+        // in local file links, which leave out unknown fields
+        if parts.first() == Some(&"local") {
+            while parts.len() > 5 && parts.last() == Some(&"") {
+                parts.pop();
+            }
+        } else {
+            parts.retain(|part| !part.is_empty());
+        }
+
+        SpotifyUri::from_parts(&parts)
+    }
+
+    fn from_parts(parts: &[&str]) -> Result<SpotifyUri, SpotifyUriError> {
+        fn id(id: &str) -> Result<SpotifyId, SpotifyUriError> {
+            SpotifyId::try_from_base62(id).map_err(SpotifyUriError::InvalidId)
+        }
+
+        match parts.len() {
+            0 => Err(SpotifyUriError::InvalidFormat),
+            2 => match parts[0] {
+                "track" => Ok(SpotifyUri::Track(try!(id(parts[1])))),
+                "album" => Ok(SpotifyUri::Album(try!(id(parts[1])))),
+                "artist" => Ok(SpotifyUri::Artist(try!(id(parts[1])))),
+                "episode" => Ok(SpotifyUri::Episode(try!(id(parts[1])))),
+                "show" => Ok(SpotifyUri::Show(try!(id(parts[1])))),
+                "audiobook" => Ok(SpotifyUri::Audiobook(try!(id(parts[1])))),
+                "chapter" => Ok(SpotifyUri::Chapter(try!(id(parts[1])))),
+                "ad" => Ok(SpotifyUri::Ad(try!(id(parts[1])))),
+                "playlist" => Ok(SpotifyUri::Playlist {
+                    user: None,
+                    id: try!(id(parts[1])),
+                }),
+                item_type => Err(SpotifyUriError::UnsupportedType(item_type.to_owned())),
+            },
+            4 if parts[0] == "user" && parts[2] == "playlist" => Ok(SpotifyUri::Playlist {
+                user: Some(parts[1].to_owned()),
+                id: try!(id(parts[3])),
+            }),
+            5 if parts[0] == "local" => Ok(SpotifyUri::Local {
+                artist: decode_component(parts[1]),
+                album: decode_component(parts[2]),
+                title: decode_component(parts[3]),
+                duration: parts[4].parse().ok(),
+            }),
+            _ => Err(SpotifyUriError::UnsupportedType(parts[0].to_owned())),
+        }
+    }
+
+    pub fn id(&self) -> Option<SpotifyId> {
+        match *self {
+            SpotifyUri::Track(id) |
+            SpotifyUri::Album(id) |
+            SpotifyUri::Artist(id) |
+            SpotifyUri::Episode(id) |
+            SpotifyUri::Show(id) |
+            SpotifyUri::Audiobook(id) |
+            SpotifyUri::Chapter(id) |
+            SpotifyUri::Ad(id) |
+            SpotifyUri::Playlist { id, .. } => Some(id),
+            SpotifyUri::Local { .. } => None,
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        match *self {
+            SpotifyUri::Local { .. } => true,
+            _ => false,
+        }
+    }
+
+    pub fn item_type(&self) -> &'static str {
+        match *self {
+            SpotifyUri::Track(_) => "track",
+            SpotifyUri::Album(_) => "album",
+            SpotifyUri::Artist(_) => "artist",
+            SpotifyUri::Episode(_) => "episode",
+            SpotifyUri::Show(_) => "show",
+            SpotifyUri::Audiobook(_) => "audiobook",
+            SpotifyUri::Chapter(_) => "chapter",
+            SpotifyUri::Ad(_) => "ad",
+            SpotifyUri::Playlist { .. } => "playlist",
+            SpotifyUri::Local { .. } => "local",
+        }
+    }
+
+    pub fn to_url(&self) -> String {
+        format!("https://open.spotify.com/{}", self.path('/'))
+    }
+
+    fn path(&self, separator: char) -> String {
+        match *self {
+            SpotifyUri::Playlist { user: Some(ref user), id } => {
+                format!("user{0}{1}{0}playlist{0}{2}", separator, user, id.to_base62())
+            }
+            SpotifyUri::Local { ref artist, ref album, ref title, duration } => {
+                format!("local{0}{1}{0}{2}{0}{3}{0}{4}", separator,
+                        encode_component(artist),
+                        encode_component(album),
+                        encode_component(title),
+                        duration.map(|d| d.to_string()).unwrap_or_default())
+            }
+            _ => {
+                let id = self.id().expect("remote items always have an id");
+                format!("{}{}{}", self.item_type(), separator, id.to_base62())
+            }
+        }
+    }
+}
+
+impl FromStr for SpotifyUri {
+    type Err = SpotifyUriError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SpotifyUri::parse(s)
+    }
+}
+
+impl fmt::Display for SpotifyUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "spotify:{}", self.path(':'))
+    }
+}
+
+// Local file fields are form-encoded, with `+` standing in for spaces.
+fn decode_component(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut data = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => data.push(b' '),
+            b'%' if i + 2 < bytes.len() && is_hex(bytes[i + 1]) && is_hex(bytes[i + 2]) => {
+                data.push(hex_value(bytes[i + 1]) << 4 | hex_value(bytes[i + 2]));
+                i += 2;
+            }
+            b => data.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&data).into_owned()
+}
+
+fn encode_component(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        match b {
+            b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'*' => {
+                encoded.push(b as char)
+            }
+            b' ' => encoded.push('+'),
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    encoded
+}
+
+fn is_hex(b: u8) -> bool {
+    (b as char).is_digit(16)
+}
+
+fn hex_value(b: u8) -> u8 {
+    (b as char).to_digit(16).unwrap() as u8
+}
+
+impl fmt::Display for SpotifyUriError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SpotifyUriError::InvalidFormat => f.write_str("not a Spotify URI or URL"),
+            SpotifyUriError::UnsupportedType(ref item_type) => {
+                write!(f, "unsupported item type {:?}", item_type)
+            }
+            SpotifyUriError::InvalidId(ref err) => write!(f, "invalid id: {}", err),
+        }
+    }
+}
+
+impl error::Error for SpotifyUriError {
+    fn description(&self) -> &str {
+        match *self {
+            SpotifyUriError::InvalidFormat => "not a Spotify URI or URL",
+            SpotifyUriError::UnsupportedType(_) => "unsupported item type",
+            SpotifyUriError::InvalidId(_) => "invalid id",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACK_ID: &'static str = "4uLU6hMCjMI75M1A2tKUQC";
+
+    fn round_trip(uri: &SpotifyUri) {
+        assert_eq!(SpotifyUri::parse(&uri.to_string()).as_ref(), Ok(uri));
+        assert_eq!(SpotifyUri::parse(&uri.to_url()).as_ref(), Ok(uri));
+    }
+
+    #[test]
+    fn parses_track() {
+        let track = SpotifyUri::Track(SpotifyId::from_base62(TRACK_ID));
+        assert_eq!(SpotifyUri::parse(&format!("spotify:track:{}", TRACK_ID)), Ok(track.clone()));
+        assert_eq!(track.to_url(), format!("https://open.spotify.com/track/{}", TRACK_ID));
+        round_trip(&track);
+    }
+
+    #[test]
+    fn parses_episode() {
+        let episode = SpotifyUri::Episode(SpotifyId::from_base62(TRACK_ID));
+        assert_eq!(SpotifyUri::parse(&format!("spotify:episode:{}", TRACK_ID)), Ok(episode.clone()));
+        round_trip(&episode);
+    }
+
+    #[test]
+    fn parses_share_links() {
+        let track = Ok(SpotifyUri::Track(SpotifyId::from_base62(TRACK_ID)));
+        let links = [
+            format!("https://open.spotify.com/track/{}", TRACK_ID),
+            format!("https://open.spotify.com/track/{}/", TRACK_ID),
+            format!("https://open.spotify.com//track/{}", TRACK_ID),
+            format!("http://play.spotify.com/track/{}?si=abc#t=10", TRACK_ID),
+            format!("https://open.spotify.com/intl-de/track/{}", TRACK_ID),
+        ];
+
+        for link in &links {
+            assert_eq!(SpotifyUri::parse(link), track, "{}", link);
+        }
+    }
+
+    #[test]
+    fn parses_local_files() {
+        let local = SpotifyUri::parse("spotify:local:Bj%C3%B6rk:Debut:Human+Behaviour%3A+Live:252").unwrap();
+        assert_eq!(local, SpotifyUri::Local {
+            artist: "Björk".to_owned(),
+            album: "Debut".to_owned(),
+            title: "Human Behaviour: Live".to_owned(),
+            duration: Some(252),
+        });
+        round_trip(&local);
+
+        let unknown = SpotifyUri::parse("spotify:local:Artist::A%2FB+%26+C:").unwrap();
+        assert_eq!(unknown, SpotifyUri::Local {
+            artist: "Artist".to_owned(),
+            album: "".to_owned(),
+            title: "A/B & C".to_owned(),
+            duration: None,
+        });
+        round_trip(&unknown);
+        assert_eq!(SpotifyUri::parse("https://open.spotify.com/local/Artist//A%2FB+%26+C/"), Ok(unknown));
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        assert_eq!(SpotifyUri::parse(""), Err(SpotifyUriError::InvalidFormat));
+        assert_eq!(SpotifyUri::parse("spotify:"), Err(SpotifyUriError::UnsupportedType("".to_owned())));
+        assert_eq!(SpotifyUri::parse("spotify:track"), Err(SpotifyUriError::UnsupportedType("track".to_owned())));
+        assert_eq!(SpotifyUri::parse("https://example.com/track/4uLU6hMCjMI75M1A2tKUQC"),
+                   Err(SpotifyUriError::InvalidFormat));
+        assert_eq!(SpotifyUri::parse("https://open.spotify.com/"), Err(SpotifyUriError::InvalidFormat));
+        assert_eq!(SpotifyUri::parse("spotify:song:4uLU6hMCjMI75M1A2tKUQC"),
+                   Err(SpotifyUriError::UnsupportedType("song".to_owned())));
+        assert_eq!(SpotifyUri::parse("spotify:track:abc"),
+                   Err(SpotifyUriError::InvalidId(SpotifyIdError::InvalidLength(3))));
+        assert_eq!(SpotifyUri::parse("spotify:track:4uLU6hMCjMI75M1A2tKUQ!"),
+                   Err(SpotifyUriError::InvalidId(SpotifyIdError::InvalidDigit('!'))));
+    }
+}
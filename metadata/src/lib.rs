@@ -77,6 +77,7 @@ pub struct Track {
     pub name: String,
     pub album: SpotifyId,
     pub artists: Vec<SpotifyId>,
+    pub duration_ms: i32,
     pub files: LinearMap<FileFormat, FileId>,
     pub alternatives: Vec<SpotifyId>,
     pub available: bool,
@@ -129,6 +130,7 @@ impl Metadata for Track {
             name: msg.get_name().to_owned(),
             album: SpotifyId::from_raw(msg.get_album().get_gid()),
             artists: artists,
+            duration_ms: msg.get_duration(),
             files: files,
             alternatives: msg.get_alternative()
                              .iter()
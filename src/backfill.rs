@@ -0,0 +1,641 @@
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use reqwest;
+use rustfm_scrobble::{self, Scrobble, ScrobbleBatch};
+use std::collections::HashSet;
+use std::error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use core::cache::Cache;
+use core::util::{now_ms, SpotifyUri};
+use history::{HistoryEntry, ScrobbleOutcome};
+use scrobbler::{self, ScrobblerConfig};
+
+// Last.fm refuses scrobbles further in the past than this
+const LASTFM_MAX_AGE_SECS: i64 = 14 * 24 * 60 * 60;
+const LASTFM_BATCH_SIZE: usize = 50;
+
+const LISTENBRAINZ_SUBMIT_URL: &'static str = "https://api.listenbrainz.org/1/submit-listens";
+const LISTENBRAINZ_BATCH_SIZE: usize = 100;
+
+/// A scrobble service plays from the history can be replayed to.
+pub trait Destination {
+    fn name(&self) -> &str;
+
+    /// Identifies the account plays are sent to, to keep track of what it
+    /// has acknowledged.
+    fn account(&self) -> &str;
+
+    fn batch_size(&self) -> usize;
+
+    /// How far back plays are accepted, in seconds.
+    fn max_age_secs(&self) -> Option<i64> {
+        None
+    }
+
+    /// Whether live scrobbles went to this service already.
+    fn scrobbles_live(&self) -> bool {
+        false
+    }
+
+    fn submit(&mut self, entries: &[HistoryEntry]) -> Result<(), String>;
+}
+
+pub struct LastFm {
+    scrobbler: rustfm_scrobble::Scrobbler,
+    username: String,
+}
+
+impl LastFm {
+    pub fn connect(config: &ScrobblerConfig, cache: Option<&Cache>) -> Result<LastFm, String> {
+        let mut lastfm = rustfm_scrobble::Scrobbler::new(&config.api_key, &config.api_secret);
+        try!(scrobbler::authenticate(&mut lastfm, config, cache).map_err(|err| err.to_string()));
+
+        Ok(LastFm {
+            scrobbler: lastfm,
+            username: config.username.clone(),
+        })
+    }
+}
+
+impl Destination for LastFm {
+    fn name(&self) -> &str {
+        "Last.fm"
+    }
+
+    fn account(&self) -> &str {
+        &self.username
+    }
+
+    fn batch_size(&self) -> usize {
+        LASTFM_BATCH_SIZE
+    }
+
+    fn max_age_secs(&self) -> Option<i64> {
+        Some(LASTFM_MAX_AGE_SECS)
+    }
+
+    fn scrobbles_live(&self) -> bool {
+        true
+    }
+
+    fn submit(&mut self, entries: &[HistoryEntry]) -> Result<(), String> {
+        let scrobbles: Vec<Scrobble> = entries.iter().map(|entry| {
+            let mut scrobble = Scrobble::new(entry.artist.as_ref().unwrap(),
+                                             entry.track.as_ref().unwrap(),
+                                             entry.album.as_ref().map_or("", String::as_str));
+            scrobble.with_timestamp(entry.timestamp as u64);
+            scrobble
+        }).collect();
+
+        self.scrobbler.scrobble_batch(&ScrobbleBatch::from(scrobbles))
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+pub struct ListenBrainz {
+    client: reqwest::Client,
+    token: String,
+}
+
+impl ListenBrainz {
+    pub fn new(token: String) -> ListenBrainz {
+        ListenBrainz {
+            client: reqwest::Client::new(),
+            token: token,
+        }
+    }
+}
+
+impl Destination for ListenBrainz {
+    fn name(&self) -> &str {
+        "ListenBrainz"
+    }
+
+    fn account(&self) -> &str {
+        &self.token
+    }
+
+    fn batch_size(&self) -> usize {
+        LISTENBRAINZ_BATCH_SIZE
+    }
+
+    fn submit(&mut self, entries: &[HistoryEntry]) -> Result<(), String> {
+        let listens: Vec<_> = entries.iter().map(|entry| {
+            // ListenBrainz wants the track's open.spotify.com link, which
+            // local files don't have
+            let spotify_id = SpotifyUri::parse(&entry.track_uri).ok()
+                .and_then(|uri| if uri.is_local() { None } else { Some(uri.to_url()) });
+
+            json!({
+                "listened_at": entry.timestamp,
+                "track_metadata": {
+                    "artist_name": entry.artist,
+                    "track_name": entry.track,
+                    "release_name": entry.album,
+                    "additional_info": {
+                        "spotify_id": spotify_id,
+                        "duration_ms": entry.duration_ms,
+                        "music_service_name": "Spotify",
+                        "device": entry.device,
+                        "submission_client": "spotify-connect-scrobbler"
+                    }
+                }
+            })
+        }).collect();
+
+        let body = json!({
+            "listen_type": "import",
+            "payload": listens
+        });
+
+        let response = try!(self.client.post(LISTENBRAINZ_SUBMIT_URL)
+            .header("Authorization", format!("Token {}", self.token))
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send()
+            .map_err(|err| err.to_string()));
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("HTTP {}", response.status()))
+        }
+    }
+}
+
+/// The plays a destination has accepted, so a backfill interrupted half way
+/// or run twice doesn't send them again.
+pub struct AckLedger {
+    path: PathBuf,
+    acked: HashSet<String>,
+}
+
+impl AckLedger {
+    /// The ledger for `destination`, kept in the account's cache directory.
+    pub fn open(dir: &Path, destination: &Destination) -> io::Result<AckLedger> {
+        // Keeps the account, which may be a token, out of the file name
+        let mut h = Sha1::new();
+        h.input_str(destination.name());
+        h.input_str(&destination.account().to_lowercase());
+        let path = dir.join(format!("backfill-{}", h.result_str()));
+
+        let mut acked = HashSet::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    acked.insert(try!(line));
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+
+        Ok(AckLedger {
+            path: path,
+            acked: acked,
+        })
+    }
+
+    fn key(entry: &HistoryEntry) -> String {
+        format!("{} {}", entry.timestamp, entry.track_uri)
+    }
+
+    pub fn contains(&self, entry: &HistoryEntry) -> bool {
+        self.acked.contains(&AckLedger::key(entry))
+    }
+
+    pub fn insert(&mut self, entries: &[HistoryEntry]) -> io::Result<()> {
+        let mut file = try!(OpenOptions::new().create(true).append(true).open(&self.path));
+        for entry in entries {
+            let key = AckLedger::key(entry);
+            try!(writeln!(file, "{}", key));
+            self.acked.insert(key);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BackfillOptions {
+    /// Seconds since the epoch, inclusive.
+    pub from: Option<i64>,
+    /// Seconds since the epoch, exclusive.
+    pub until: Option<i64>,
+    /// Also replays plays already scrobbled live, e.g. to a new Last.fm account.
+    pub include_scrobbled: bool,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BackfillReport {
+    pub submitted: usize,
+    pub already_acknowledged: usize,
+    /// Plays too far in the past for the destination to accept.
+    pub too_old: usize,
+}
+
+#[derive(Debug)]
+pub enum BackfillError {
+    Io(io::Error),
+    /// The destination refused a batch, after `submitted` plays went through.
+    Submit { submitted: usize, reason: String },
+}
+
+/// Replays the plays in `entries` which are worth scrobbling to
+/// `destination`, oldest first and in batches, with their original
+/// timestamps.
+pub fn backfill(entries: Vec<HistoryEntry>, destination: &mut Destination, ledger: &mut AckLedger,
+                options: &BackfillOptions)
+    -> Result<BackfillReport, BackfillError>
+{
+    let mut report = BackfillReport::default();
+    let oldest = destination.max_age_secs().map(|max_age| now_ms() / 1000 - max_age);
+
+    let mut pending: Vec<HistoryEntry> = Vec::new();
+    for entry in entries {
+        let eligible = match entry.outcome {
+            ScrobbleOutcome::Failed => true,
+            ScrobbleOutcome::Scrobbled => options.include_scrobbled || !destination.scrobbles_live(),
+            ScrobbleOutcome::Excluded | ScrobbleOutcome::TooShort => false,
+        };
+
+        if !eligible || entry.track.is_none() || entry.artist.is_none() ||
+           options.from.map_or(false, |from| entry.timestamp < from) ||
+           options.until.map_or(false, |until| entry.timestamp >= until) {
+            continue;
+        }
+
+        if ledger.contains(&entry) {
+            report.already_acknowledged += 1;
+        } else if oldest.map_or(false, |oldest| entry.timestamp < oldest) {
+            report.too_old += 1;
+        } else {
+            pending.push(entry);
+        }
+    }
+
+    pending.sort_by_key(|entry| entry.timestamp);
+
+    for batch in pending.chunks(destination.batch_size()) {
+        if !options.dry_run {
+            try!(destination.submit(batch).map_err(|reason| BackfillError::Submit {
+                submitted: report.submitted,
+                reason: reason,
+            }));
+            try!(ledger.insert(batch));
+        }
+
+        report.submitted += batch.len();
+        info!("{} {} of {} plays to {}", if options.dry_run { "Would send" } else { "Sent" },
+              report.submitted, pending.len(), destination.name());
+    }
+
+    Ok(report)
+}
+
+/// Seconds since the epoch at the start of a `YYYY-MM-DD` day, in UTC.
+pub fn parse_date(date: &str) -> Option<i64> {
+    let parts: Vec<&str> = date.split('-').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+
+    let year: i64 = match parts[0].parse() { Ok(year) => year, Err(_) => return None };
+    let month: i64 = match parts[1].parse() { Ok(month) => month, Err(_) => return None };
+    let day: i64 = match parts[2].parse() { Ok(day) => day, Err(_) => return None };
+
+    let leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap_year => 29,
+        2 => 28,
+        _ => return None,
+    };
+    if day < 1 || day > days_in_month {
+        return None;
+    }
+
+    // Days from the civil calendar, as in Howard Hinnant's algorithm
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    Some(days * 24 * 60 * 60)
+}
+
+impl From<io::Error> for BackfillError {
+    fn from(err: io::Error) -> BackfillError {
+        BackfillError::Io(err)
+    }
+}
+
+impl fmt::Display for BackfillError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BackfillError::Io(ref err) => write!(f, "cannot record acknowledged plays: {}", err),
+            BackfillError::Submit { submitted, ref reason } => {
+                write!(f, "submission failed after {} plays: {}", submitted, reason)
+            }
+        }
+    }
+}
+
+impl error::Error for BackfillError {
+    fn description(&self) -> &str {
+        match *self {
+            BackfillError::Io(ref err) => error::Error::description(err),
+            BackfillError::Submit { .. } => "submission failed",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const DAY_SECS: i64 = 24 * 60 * 60;
+
+    struct FakeDestination {
+        batch_size: usize,
+        max_age_secs: Option<i64>,
+        scrobbles_live: bool,
+        /// The timestamps of the plays in each batch submitted.
+        batches: Vec<Vec<i64>>,
+        /// Refuses the batch with this index.
+        fail_batch: Option<usize>,
+    }
+
+    impl FakeDestination {
+        fn new() -> FakeDestination {
+            FakeDestination {
+                batch_size: 50,
+                max_age_secs: None,
+                scrobbles_live: true,
+                batches: Vec::new(),
+                fail_batch: None,
+            }
+        }
+
+        fn submitted(&self) -> Vec<i64> {
+            self.batches.iter().flat_map(|batch| batch.iter().cloned()).collect()
+        }
+    }
+
+    impl Destination for FakeDestination {
+        fn name(&self) -> &str {
+            "Fake"
+        }
+
+        fn account(&self) -> &str {
+            "someone"
+        }
+
+        fn batch_size(&self) -> usize {
+            self.batch_size
+        }
+
+        fn max_age_secs(&self) -> Option<i64> {
+            self.max_age_secs
+        }
+
+        fn scrobbles_live(&self) -> bool {
+            self.scrobbles_live
+        }
+
+        fn submit(&mut self, entries: &[HistoryEntry]) -> Result<(), String> {
+            if self.fail_batch == Some(self.batches.len()) {
+                return Err("HTTP 503".to_owned());
+            }
+            self.batches.push(entries.iter().map(|entry| entry.timestamp).collect());
+            Ok(())
+        }
+    }
+
+    fn entry(timestamp: i64, outcome: ScrobbleOutcome) -> HistoryEntry {
+        HistoryEntry {
+            timestamp: timestamp,
+            track_uri: "spotify:track:4uLU6hMCjMI75M1A2tKUQC".to_owned(),
+            track: Some("Never Gonna Give You Up".to_owned()),
+            artist_uri: None,
+            artist: Some("Rick Astley".to_owned()),
+            album_uri: None,
+            album: None,
+            duration_ms: Some(213573),
+            played_ms: 200000,
+            device: "Kitchen".to_owned(),
+            device_ident: "0123456789abcdef".to_owned(),
+            context_uri: None,
+            outcome: outcome,
+        }
+    }
+
+    fn failed(timestamps: &[i64]) -> Vec<HistoryEntry> {
+        timestamps.iter().map(|&timestamp| entry(timestamp, ScrobbleOutcome::Failed)).collect()
+    }
+
+    /// An empty directory of its own for each test.
+    fn test_dir(name: &str) -> PathBuf {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let dir = env::temp_dir().join(format!("backfill-{}-{}{}", name, now.as_secs(), now.subsec_nanos()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Backfills `entries` with a fresh ledger in `dir`.
+    fn run(dir: &Path, entries: Vec<HistoryEntry>, destination: &mut FakeDestination, options: &BackfillOptions)
+        -> Result<BackfillReport, BackfillError>
+    {
+        fs::create_dir_all(dir).unwrap();
+        let mut ledger = AckLedger::open(dir, destination).unwrap();
+        backfill(entries, destination, &mut ledger, options)
+    }
+
+    #[test]
+    fn replays_eligible_outcomes() {
+        let dir = test_dir("outcomes");
+        let entries = vec![
+            entry(100, ScrobbleOutcome::Scrobbled),
+            entry(200, ScrobbleOutcome::Excluded),
+            entry(300, ScrobbleOutcome::TooShort),
+            entry(400, ScrobbleOutcome::Failed),
+        ];
+        let dry_run = BackfillOptions { dry_run: true, ..BackfillOptions::default() };
+        let include_scrobbled = BackfillOptions { include_scrobbled: true, ..BackfillOptions::default() };
+
+        // Scrobbled plays went to a live destination already
+        let mut live = FakeDestination::new();
+        assert_eq!(run(&dir, entries.clone(), &mut live, &dry_run).unwrap().submitted, 1);
+        run(&dir, entries.clone(), &mut live, &BackfillOptions::default()).unwrap();
+        assert_eq!(live.submitted(), vec![400]);
+        // A fresh ledger for each of the following
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut live = FakeDestination::new();
+        run(&dir, entries.clone(), &mut live, &include_scrobbled).unwrap();
+        assert_eq!(live.submitted(), vec![100, 400]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut other = FakeDestination::new();
+        other.scrobbles_live = false;
+        run(&dir, entries, &mut other, &BackfillOptions::default()).unwrap();
+        assert_eq!(other.submitted(), vec![100, 400]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_to_date_range() {
+        let dir = test_dir("range");
+        let options = BackfillOptions {
+            from: Some(200),
+            until: Some(400),
+            ..BackfillOptions::default()
+        };
+
+        let mut destination = FakeDestination::new();
+        let report = run(&dir, failed(&[100, 199, 200, 300, 399, 400, 500]), &mut destination, &options).unwrap();
+        assert_eq!(destination.submitted(), vec![200, 300, 399]);
+        assert_eq!(report.submitted, 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_incomplete_entries() {
+        let dir = test_dir("incomplete");
+        let mut entries = failed(&[100, 200, 300]);
+        entries[0].track = None;
+        entries[1].artist = None;
+
+        let mut destination = FakeDestination::new();
+        let report = run(&dir, entries, &mut destination, &BackfillOptions::default()).unwrap();
+        assert_eq!(destination.submitted(), vec![300]);
+        assert_eq!((report.submitted, report.already_acknowledged, report.too_old), (1, 0, 0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_acknowledged_plays() {
+        let dir = test_dir("ledger");
+
+        let mut destination = FakeDestination::new();
+        run(&dir, failed(&[100, 200]), &mut destination, &BackfillOptions::default()).unwrap();
+
+        // Read back from the ledger file
+        let mut destination = FakeDestination::new();
+        let report = run(&dir, failed(&[100, 200, 300]), &mut destination, &BackfillOptions::default()).unwrap();
+        assert_eq!(destination.submitted(), vec![300]);
+        assert_eq!((report.submitted, report.already_acknowledged), (1, 2));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn counts_plays_too_old() {
+        let dir = test_dir("too-old");
+        let now = now_ms() / 1000;
+
+        let mut destination = FakeDestination::new();
+        destination.max_age_secs = Some(14 * DAY_SECS);
+        let report = run(&dir, failed(&[now - 15 * DAY_SECS, now - 13 * DAY_SECS, now - DAY_SECS]),
+                         &mut destination, &BackfillOptions::default()).unwrap();
+
+        assert_eq!(destination.submitted(), vec![now - 13 * DAY_SECS, now - DAY_SECS]);
+        assert_eq!((report.submitted, report.too_old), (2, 1));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sends_oldest_first_in_batches() {
+        let dir = test_dir("batches");
+
+        let mut destination = FakeDestination::new();
+        destination.batch_size = 2;
+        let report = run(&dir, failed(&[500, 100, 400, 200, 300]), &mut destination,
+                         &BackfillOptions::default()).unwrap();
+
+        assert_eq!(destination.batches, vec![vec![100, 200], vec![300, 400], vec![500]]);
+        assert_eq!(report.submitted, 5);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run_sends_nothing() {
+        let dir = test_dir("dry-run");
+        let options = BackfillOptions { dry_run: true, ..BackfillOptions::default() };
+
+        let mut destination = FakeDestination::new();
+        let mut ledger = AckLedger::open(&dir, &destination).unwrap();
+        let report = backfill(failed(&[100, 200]), &mut destination, &mut ledger, &options).unwrap();
+
+        assert_eq!(report.submitted, 2);
+        assert!(destination.batches.is_empty());
+        assert!(!ledger.contains(&entry(100, ScrobbleOutcome::Failed)));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_batch_is_not_acknowledged() {
+        let dir = test_dir("failed");
+
+        let mut destination = FakeDestination::new();
+        destination.batch_size = 2;
+        destination.fail_batch = Some(1);
+        match run(&dir, failed(&[100, 200, 300, 400, 500]), &mut destination, &BackfillOptions::default()) {
+            Err(BackfillError::Submit { submitted, ref reason }) => {
+                assert_eq!(submitted, 2);
+                assert_eq!(reason, "HTTP 503");
+            }
+            other => panic!("expected a failed submission, got {:?}", other),
+        }
+
+        let ledger = AckLedger::open(&dir, &destination).unwrap();
+        assert!(ledger.contains(&entry(200, ScrobbleOutcome::Failed)));
+        assert!(!ledger.contains(&entry(300, ScrobbleOutcome::Failed)));
+
+        // Picked up where it failed the next time
+        let mut destination = FakeDestination::new();
+        let report = run(&dir, failed(&[100, 200, 300, 400, 500]), &mut destination,
+                         &BackfillOptions::default()).unwrap();
+        assert_eq!(destination.submitted(), vec![300, 400, 500]);
+        assert_eq!(report.already_acknowledged, 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("1970-01-02"), Some(DAY_SECS));
+        assert_eq!(parse_date("2000-03-01"), Some(951868800));
+        assert_eq!(parse_date("2017-06-01"), Some(1496275200));
+        assert_eq!(parse_date("2024-02-29"), Some(1709164800));
+        assert_eq!(parse_date("2000-02-29"), Some(951782400));
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        for date in &["", "2017", "2017-06", "2017-06-01-01", "2017/06/01", "2017-06-xx", "yyyy-06-01",
+                      "2017-00-10", "2017-13-01", "2017-06-00", "2017-06-31", "2023-04-31", "2024-02-30",
+                      "2023-02-29", "1900-02-29", "2017-01-32"] {
+            assert_eq!(parse_date(date), None, "{}", date);
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use toml;
+
+use core::secrets::SecretError;
+
+/// Daemon settings read from a TOML file. Every value is optional here,
+/// command line flags and environment variables fill in or override them.
+///
+/// ```toml
+/// [session]
+/// username = "spotify-user"
+///
+/// [connect]
+/// name = "Scrobbler"
+/// device_type = "speaker"
+/// discovery = true
+/// hidden = false
+/// rename = false
+/// playback_commands = "hand-off"
+///
+/// [cache]
+/// path = "/var/cache/spotify-connect-scrobbler"
+///
+/// [proxy]
+/// url = "http://proxy.example.com:3128"
+///
+/// [history]
+/// format = "jsonl"
+/// max_size_mb = 10
+/// keep = 5
+///
+/// [stats]
+/// enabled = true
+///
+/// [http]
+/// listen = "127.0.0.1:8080"
+/// token = "file:/run/secrets/scrobbler-api"
+///
+/// [[webhook]]
+/// url = "https://hooks.example.com/scrobbler"
+/// events = ["now_playing", "scrobble"]
+/// secret = "file:/run/secrets/scrobbler-webhook"
+/// template = '{"text": "{{account}} is listening to {{track}} by {{artists}}"}'
+///
+/// [scrobbler]
+/// lastfm_username = "lastfm-user"
+/// lastfm_api_key = "..."
+/// exclude_content = ["episode", "audiobook"]
+///
+/// [[account]]
+/// id = "alice"
+/// name = "Alice's Scrobbler"
+/// spotify_username = "alice"
+/// lastfm_username = "alice-fm"
+/// ```
+///
+/// With one or more `[[account]]` tables, one session is run per account and
+/// the `[connect]` and `[scrobbler]` sections only provide defaults for them.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub verbose: Option<bool>,
+    #[serde(default)]
+    pub session: SessionSection,
+    #[serde(default)]
+    pub connect: ConnectSection,
+    #[serde(default)]
+    pub cache: CacheSection,
+    #[serde(default)]
+    pub proxy: ProxySection,
+    #[serde(default)]
+    pub scrobbler: ScrobblerSection,
+    #[serde(default)]
+    pub history: HistorySection,
+    #[serde(default)]
+    pub stats: StatsSection,
+    #[serde(default)]
+    pub http: HttpSection,
+    #[serde(default)]
+    pub webhook: Vec<WebhookSection>,
+    #[serde(default)]
+    pub account: Vec<AccountSection>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SessionSection {
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectSection {
+    pub name: Option<String>,
+    pub device_type: Option<String>,
+    pub discovery: Option<bool>,
+    /// Hides the device from device pickers and refuses playback.
+    pub hidden: Option<bool>,
+    /// Lets clients rename the device.
+    pub rename: Option<bool>,
+    /// `reject` or `hand-off`, see `CommandPolicy`.
+    pub playback_commands: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct CacheSection {
+    pub path: Option<String>,
+    /// Encrypts cached credentials, the machine id is used when not given.
+    pub passphrase: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ProxySection {
+    pub url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ScrobblerSection {
+    pub lastfm_username: Option<String>,
+    pub lastfm_password: Option<String>,
+    pub lastfm_api_key: Option<String>,
+    pub lastfm_api_secret: Option<String>,
+    pub exclude_content: Option<Vec<String>>,
+    pub include_devices: Option<Vec<String>>,
+    pub exclude_devices: Option<Vec<String>>,
+}
+
+/// The play history log kept in the cache directory, see `HistoryLog`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct HistorySection {
+    /// `jsonl` or `csv`, no history is kept when not given.
+    pub format: Option<String>,
+    pub max_size_mb: Option<u64>,
+    pub keep: Option<usize>,
+}
+
+/// The listening statistics database kept in the cache directory, in builds
+/// with the `stats` feature.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct StatsSection {
+    pub enabled: Option<bool>,
+}
+
+/// The local status and control API, see `http_api`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct HttpSection {
+    /// Address to listen on, the API is off when not given.
+    pub listen: Option<String>,
+    /// Bearer token required by every request.
+    pub token: Option<String>,
+}
+
+/// A URL notified of the plays of every account, see `webhooks`.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookSection {
+    pub url: String,
+    /// `now_playing` and/or `scrobble`, all events when not given.
+    pub events: Option<Vec<String>>,
+    /// Key the bodies are signed with.
+    pub secret: Option<String>,
+    /// Body sent instead of the default JSON, with `{{placeholders}}`.
+    pub template: Option<String>,
+}
+
+/// One Spotify account scrobbled by a multi-account daemon. Settings left
+/// out fall back to the `[connect]` and `[scrobbler]` sections, except for
+/// the credentials, the device name and the Last.fm account.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AccountSection {
+    /// Names the account's cache subdirectory, defaults to the Spotify username.
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub device_type: Option<String>,
+    pub discovery: Option<bool>,
+    pub hidden: Option<bool>,
+    pub rename: Option<bool>,
+    pub playback_commands: Option<String>,
+    pub spotify_username: Option<String>,
+    pub spotify_password: Option<String>,
+    pub lastfm_username: Option<String>,
+    pub lastfm_password: Option<String>,
+    pub lastfm_api_key: Option<String>,
+    pub lastfm_api_secret: Option<String>,
+    pub exclude_content: Option<Vec<String>>,
+    pub include_devices: Option<Vec<String>>,
+    pub exclude_devices: Option<Vec<String>>,
+}
+
+impl AccountSection {
+    /// Fills in the settings an account may share with the others.
+    pub fn inherit(self, defaults: &AccountSection) -> AccountSection {
+        AccountSection {
+            device_type: self.device_type.or_else(|| defaults.device_type.clone()),
+            discovery: self.discovery.or(defaults.discovery),
+            hidden: self.hidden.or(defaults.hidden),
+            rename: self.rename.or(defaults.rename),
+            playback_commands: self.playback_commands.or_else(|| defaults.playback_commands.clone()),
+            lastfm_api_key: self.lastfm_api_key.or_else(|| defaults.lastfm_api_key.clone()),
+            lastfm_api_secret: self.lastfm_api_secret.or_else(|| defaults.lastfm_api_secret.clone()),
+            exclude_content: self.exclude_content.or_else(|| defaults.exclude_content.clone()),
+            include_devices: self.include_devices.or_else(|| defaults.include_devices.clone()),
+            exclude_devices: self.exclude_devices.or_else(|| defaults.exclude_devices.clone()),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, String),
+    /// A required setting was given neither in the file, on the command line
+    /// nor in the environment.
+    Missing {
+        key: &'static str,
+        flag: &'static str,
+        env: Option<&'static str>,
+    },
+    Invalid {
+        key: &'static str,
+        value: String,
+        reason: String,
+    },
+    Secret(&'static str, SecretError),
+    /// A setting that has to be given per account in a `[[account]]` table.
+    MissingAccountSetting {
+        account: String,
+        key: &'static str,
+    },
+    DuplicateAccount(String),
+}
+
+impl ConfigFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ConfigFile, ConfigError> {
+        let path = path.as_ref();
+
+        let mut contents = String::new();
+        try!(File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|err| ConfigError::Io(path.to_owned(), err)));
+
+        toml::from_str(&contents)
+            .map_err(|err| ConfigError::Parse(path.to_owned(), err.to_string()))
+    }
+}
+
+impl ConfigError {
+    pub fn invalid<V: Into<String>, R: Into<String>>(key: &'static str, value: V, reason: R) -> ConfigError {
+        ConfigError::Invalid {
+            key: key,
+            value: value.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref path, ref err) => {
+                write!(f, "cannot read config file {}: {}", path.display(), err)
+            }
+            ConfigError::Parse(ref path, ref err) => {
+                write!(f, "invalid config file {}: {}", path.display(), err)
+            }
+            ConfigError::Missing { key, flag, env: Some(env) } => {
+                write!(f, "missing setting `{}` (or --{} / ${})", key, flag, env)
+            }
+            ConfigError::Missing { key, flag, env: None } => {
+                write!(f, "missing setting `{}` (or --{})", key, flag)
+            }
+            ConfigError::Invalid { key, ref value, ref reason } => {
+                write!(f, "invalid value {:?} for `{}`: {}", value, key, reason)
+            }
+            ConfigError::Secret(key, ref err) => {
+                write!(f, "cannot resolve secret `{}`: {}", key, err)
+            }
+            ConfigError::MissingAccountSetting { ref account, key } => {
+                write!(f, "missing setting `{}` for account {:?}", key, account)
+            }
+            ConfigError::DuplicateAccount(ref account) => {
+                write!(f, "account {:?} is configured twice, give each account its own `id` and `name`",
+                       account)
+            }
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn description(&self) -> &str {
+        match *self {
+            ConfigError::Io(_, ref err) => error::Error::description(err),
+            ConfigError::Parse(..) => "invalid config file",
+            ConfigError::Missing { .. } => "missing setting",
+            ConfigError::Invalid { .. } => "invalid setting",
+            ConfigError::Secret(_, ref err) => error::Error::description(err),
+            ConfigError::MissingAccountSetting { .. } => "missing account setting",
+            ConfigError::DuplicateAccount(_) => "duplicate account",
+        }
+    }
+}
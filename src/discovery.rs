@@ -0,0 +1,288 @@
+use base64;
+use crypto;
+use crypto::digest::Digest;
+use crypto::mac::Mac;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Stream, BoxFuture, Poll};
+use hyper::server::{Service, Request, Response, Http};
+use hyper::{self, Get, Post, StatusCode};
+use hyper::header::ContentType;
+use mdns;
+use rand;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use tokio_core::reactor::Handle;
+use url;
+
+use core::authentication::Credentials;
+use core::config::ConnectConfig;
+use core::diffie_hellman::DHLocalKeys;
+
+// addUser requests are a few kilobytes at most
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Serves the `getInfo` and `addUser` endpoints of the Spotify Connect
+/// zeroconf protocol, see `docs/authentication.md`.
+#[derive(Clone)]
+struct Discovery(Arc<DiscoveryInner>);
+struct DiscoveryInner {
+    config: ConnectConfig,
+    device_id: String,
+    keys: DHLocalKeys,
+    tx: mpsc::UnboundedSender<Credentials>,
+}
+
+impl Discovery {
+    pub fn new(config: ConnectConfig, device_id: String)
+        -> (Discovery, mpsc::UnboundedReceiver<Credentials>)
+    {
+        let (tx, rx) = mpsc::unbounded();
+
+        let discovery = Discovery(Arc::new(DiscoveryInner {
+            config: config,
+            device_id: device_id,
+            keys: DHLocalKeys::random(&mut rand::thread_rng()),
+            tx: tx,
+        }));
+
+        (discovery, rx)
+    }
+}
+
+impl Discovery {
+    fn handle_get_info(&self, _params: &BTreeMap<String, String>)
+        -> ::futures::Finished<Response, hyper::Error>
+    {
+        let public_key = base64::encode(&self.0.keys.public_key());
+
+        let result = json!({
+            "status": 101,
+            "statusString": "ERROR-OK",
+            "spotifyError": 0,
+            "version": "2.1.0",
+            "deviceID": (self.0.device_id),
+            "remoteName": (self.0.config.name),
+            "activeUser": "",
+            "publicKey": (public_key),
+            "deviceType": (self.0.config.device_type.to_string().to_uppercase()),
+            "libraryVersion": "0.1.0",
+            "accountReq": "PREMIUM",
+            "brandDisplayName": "librespot",
+            "modelDisplayName": "spotify-connect-scrobbler"
+        });
+
+        ::futures::finished(json_response(result.to_string()))
+    }
+
+    fn handle_add_user(&self, params: &BTreeMap<String, String>)
+        -> ::futures::Finished<Response, hyper::Error>
+    {
+        let (status, result) = match self.decrypt_credentials(params) {
+            Ok(credentials) => {
+                info!("Received credentials for \"{}\" through discovery", credentials.username);
+
+                if mpsc::UnboundedSender::send(&self.0.tx, credentials).is_ok() {
+                    (StatusCode::Ok, json!({
+                        "status": 101,
+                        "spotifyError": 0,
+                        "statusString": "ERROR-OK"
+                    }))
+                } else {
+                    // The account is shutting down
+                    warn!("Dropped discovery login, nothing is waiting for it");
+
+                    (StatusCode::ServiceUnavailable, json!({
+                        "status": 103,
+                        "spotifyError": 1,
+                        "statusString": "ERROR-UNKNOWN"
+                    }))
+                }
+            }
+            Err(err) => {
+                warn!("Rejected discovery login: {}", err);
+
+                (StatusCode::Ok, json!({
+                    "status": 102,
+                    "spotifyError": 1,
+                    "statusString": "ERROR-BAD-REQUEST"
+                }))
+            }
+        };
+
+        ::futures::finished(json_response(result.to_string()).with_status(status))
+    }
+
+    fn decrypt_credentials(&self, params: &BTreeMap<String, String>)
+        -> Result<Credentials, String>
+    {
+        let username = try!(params.get("userName").ok_or("missing userName"));
+        let encrypted_blob = try!(params.get("blob").ok_or("missing blob"));
+        let client_key = try!(params.get("clientKey").ok_or("missing clientKey"));
+
+        let encrypted_blob = try!(base64::decode(encrypted_blob).map_err(|_| "invalid blob"));
+        let client_key = try!(base64::decode(client_key).map_err(|_| "invalid clientKey"));
+
+        // IV, at least one byte of payload, then the MAC
+        if encrypted_blob.len() <= 0x10 + 0x14 {
+            return Err("blob too short".to_owned());
+        }
+
+        let shared_key = self.0.keys.shared_secret(&client_key);
+
+        let iv = &encrypted_blob[0..0x10];
+        let encrypted = &encrypted_blob[0x10..encrypted_blob.len() - 0x14];
+        let cksum = &encrypted_blob[encrypted_blob.len() - 0x14..];
+
+        let base_key = {
+            let mut data = [0u8; 20];
+            let mut h = crypto::sha1::Sha1::new();
+            h.input(&shared_key);
+            h.result(&mut data);
+            data[..16].to_owned()
+        };
+
+        let checksum_key = {
+            let mut h = crypto::hmac::Hmac::new(crypto::sha1::Sha1::new(), &base_key);
+            h.input(b"checksum");
+            h.result().code().to_owned()
+        };
+
+        let encryption_key = {
+            let mut h = crypto::hmac::Hmac::new(crypto::sha1::Sha1::new(), &base_key);
+            h.input(b"encryption");
+            h.result().code().to_owned()
+        };
+
+        let mac = {
+            let mut h = crypto::hmac::Hmac::new(crypto::sha1::Sha1::new(), &checksum_key);
+            h.input(encrypted);
+            h.result().code().to_owned()
+        };
+
+        if !crypto::util::fixed_time_eq(&mac, cksum) {
+            return Err("MAC mismatch".to_owned());
+        }
+
+        let decrypted = {
+            let mut data = vec![0u8; encrypted.len()];
+            let mut cipher = crypto::aes::ctr(crypto::aes::KeySize::KeySize128,
+                                              &encryption_key[0..16],
+                                              iv);
+            cipher.process(encrypted, &mut data);
+            try!(String::from_utf8(data).map_err(|_| "decrypted blob is not valid UTF-8"))
+        };
+
+        Credentials::with_blob(username.to_owned(), &decrypted, &self.0.device_id)
+            .map_err(|err| err.to_string())
+    }
+
+    fn not_found(&self)
+        -> ::futures::Finished<Response, hyper::Error>
+    {
+        ::futures::finished(Response::new().with_status(StatusCode::NotFound))
+    }
+}
+
+fn json_response(body: String) -> Response {
+    Response::new()
+        .with_header(ContentType::json())
+        .with_body(body)
+}
+
+impl Service for Discovery {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = BoxFuture<Response, hyper::Error>;
+
+    fn call(&self, request: Request) -> Self::Future {
+        let mut params = BTreeMap::new();
+
+        let (method, uri, _, _, body) = request.deconstruct();
+        if let Some(query) = uri.query() {
+            params.extend(url::form_urlencoded::parse(query.as_bytes()).into_owned());
+        }
+
+        if method != Get {
+            debug!("{:?} {:?} {:?}", method, uri.path(), params);
+        }
+
+        let this = self.clone();
+        body.fold(Vec::new(), |mut acc, chunk| {
+            if acc.len() + chunk.len() > MAX_BODY_SIZE {
+                return Err(hyper::Error::TooLarge);
+            }
+            acc.extend_from_slice(chunk.as_ref());
+            Ok(acc)
+        }).map(move |body| {
+            params.extend(url::form_urlencoded::parse(&body).into_owned());
+            params
+        }).and_then(move |params| {
+            match (method, params.get("action").map(AsRef::as_ref)) {
+                (Get, Some("getInfo")) => this.handle_get_info(&params),
+                (Post, Some("addUser")) => this.handle_add_user(&params),
+                _ => this.not_found(),
+            }
+        }).boxed()
+    }
+}
+
+/// Credentials received from Spotify clients on the local network. The mDNS
+/// advertisement is withdrawn and the HTTP server stopped when the stream is
+/// dropped.
+pub struct DiscoveryStream {
+    credentials: mpsc::UnboundedReceiver<Credentials>,
+    _svc: mdns::Service,
+    _shutdown: oneshot::Sender<()>,
+}
+
+pub fn discovery(handle: &Handle, config: ConnectConfig, device_id: String)
+    -> io::Result<DiscoveryStream>
+{
+    let (discovery, creds_rx) = Discovery::new(config.clone(), device_id);
+
+    let serve = {
+        let http = Http::new();
+        try!(http.serve_addr_handle(&"0.0.0.0:0".parse().unwrap(), handle, move || Ok(discovery.clone()))
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err)))
+    };
+    let addr = serve.incoming_ref().local_addr();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let server_future = {
+        let handle = handle.clone();
+        serve.for_each(move |connection| {
+            handle.spawn(connection.then(|_| Ok(())));
+            Ok(())
+        })
+        // Stops accepting connections once the stream, and the sender with it, is dropped
+        .select(shutdown_rx.then(|_| Ok(())))
+        .then(|_| Ok(()))
+    };
+    handle.spawn(server_future);
+
+    debug!("Discovery listening on port {}", addr.port());
+
+    let responder = try!(mdns::Responder::spawn(handle));
+    let svc = responder.register(
+        "_spotify-connect._tcp".to_owned(),
+        config.name,
+        addr.port(),
+        &["VERSION=1.0", "CPath=/"]);
+
+    Ok(DiscoveryStream {
+        credentials: creds_rx,
+        _svc: svc,
+        _shutdown: shutdown_tx,
+    })
+}
+
+impl Stream for DiscoveryStream {
+    type Item = Credentials;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.credentials.poll()
+    }
+}
@@ -0,0 +1,491 @@
+use serde_json;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// How the history log is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryFormat {
+    /// One JSON object per line.
+    JsonLines,
+    Csv,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryConfig {
+    pub format: HistoryFormat,
+    /// Size at which the log is rotated.
+    pub max_bytes: u64,
+    /// Rotated logs kept besides the current one.
+    pub keep: usize,
+}
+
+/// What became of a play.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrobbleOutcome {
+    #[serde(rename = "scrobbled")]
+    Scrobbled,
+    /// Not scrobbled because of the content or device filters.
+    #[serde(rename = "excluded")]
+    Excluded,
+    /// Not played for long enough to be scrobbled.
+    #[serde(rename = "too_short")]
+    TooShort,
+    #[serde(rename = "failed")]
+    Failed,
+}
+
+/// A play, as recorded in the history log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    /// When the track started, in seconds since the epoch.
+    pub timestamp: i64,
+    pub track_uri: String,
+    pub track: Option<String>,
+    pub artist_uri: Option<String>,
+    pub artist: Option<String>,
+    pub album_uri: Option<String>,
+    pub album: Option<String>,
+    pub duration_ms: Option<u32>,
+    pub played_ms: u64,
+    pub device: String,
+    pub device_ident: String,
+    pub context_uri: Option<String>,
+    pub outcome: ScrobbleOutcome,
+}
+
+/// Somewhere besides the history log plays are recorded, such as the
+/// statistics database.
+pub trait PlayStore: fmt::Debug {
+    fn record(&self, entry: &HistoryEntry) -> Result<(), String>;
+}
+
+const CSV_HEADER: &'static str = "timestamp,track_uri,track,artist_uri,artist,album_uri,album,\
+                                  duration_ms,played_ms,device,device_ident,context_uri,outcome";
+
+/// An append-only log of every play observed, kept independently of Last.fm
+/// so the listening history can be archived or imported elsewhere.
+///
+/// Once the log grows past `max_bytes` it is renamed to `history.jsonl.1`
+/// (or `history.csv.1`), shifting older logs up and dropping the oldest.
+#[derive(Debug, Clone)]
+pub struct HistoryLog {
+    path: PathBuf,
+    config: HistoryConfig,
+}
+
+impl HistoryLog {
+    pub fn new(dir: &Path, config: HistoryConfig) -> HistoryLog {
+        let file_name = match config.format {
+            HistoryFormat::JsonLines => "history.jsonl",
+            HistoryFormat::Csv => "history.csv",
+        };
+
+        HistoryLog {
+            path: dir.join(file_name),
+            config: config,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&self, entry: &HistoryEntry) -> io::Result<()> {
+        let mut line = match self.config.format {
+            HistoryFormat::JsonLines => serde_json::to_string(entry).unwrap(),
+            HistoryFormat::Csv => csv_record(entry),
+        };
+        line.push('\n');
+
+        let size = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+
+        let size = if size > 0 && size + line.len() as u64 > self.config.max_bytes {
+            try!(self.rotate());
+            0
+        } else {
+            size
+        };
+
+        let mut file = try!(OpenOptions::new().create(true).append(true).open(&self.path));
+        if size == 0 && self.config.format == HistoryFormat::Csv {
+            try!(writeln!(file, "{}", CSV_HEADER));
+        }
+        file.write_all(line.as_bytes())
+    }
+
+    /// Every entry in the log, oldest first, including those in rotated logs.
+    pub fn read(&self) -> io::Result<Vec<HistoryEntry>> {
+        let mut entries = Vec::new();
+
+        let paths = (1..self.config.keep + 1).rev()
+            .map(|index| self.rotated_path(index))
+            .chain(Some(self.path.clone()));
+
+        for path in paths {
+            let mut contents = String::new();
+            match File::open(&path).and_then(|mut file| file.read_to_string(&mut contents)) {
+                Ok(_) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            }
+
+            let parsed = match self.config.format {
+                HistoryFormat::JsonLines => parse_json_lines(&contents),
+                HistoryFormat::Csv => parse_csv(&contents),
+            };
+            match parsed {
+                Ok(parsed) => entries.extend(parsed),
+                Err(err) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("{}: {}", path.display(), err)));
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.config.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+
+        match fs::remove_file(self.rotated_path(self.config.keep)) {
+            Ok(()) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+
+        for index in (1..self.config.keep).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                try!(fs::rename(&from, self.rotated_path(index + 1)));
+            }
+        }
+
+        fs::rename(&self.path, self.rotated_path(1))
+    }
+}
+
+fn csv_record(entry: &HistoryEntry) -> String {
+    let optional = |value: &Option<String>| value.as_ref().map(|value| csv_field(value)).unwrap_or_default();
+
+    [
+        entry.timestamp.to_string(),
+        csv_field(&entry.track_uri),
+        optional(&entry.track),
+        optional(&entry.artist_uri),
+        optional(&entry.artist),
+        optional(&entry.album_uri),
+        optional(&entry.album),
+        entry.duration_ms.map(|duration| duration.to_string()).unwrap_or_default(),
+        entry.played_ms.to_string(),
+        csv_field(&entry.device),
+        csv_field(&entry.device_ident),
+        optional(&entry.context_uri),
+        entry.outcome.to_string(),
+    ].join(",")
+}
+
+fn parse_json_lines(contents: &str) -> Result<Vec<HistoryEntry>, String> {
+    contents.lines()
+        .enumerate()
+        .filter(|&(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).map_err(|err| format!("line {}: {}", index + 1, err))
+        })
+        .collect()
+}
+
+fn parse_csv(contents: &str) -> Result<Vec<HistoryEntry>, String> {
+    let mut entries = Vec::new();
+
+    for (index, record) in csv_records(contents).into_iter().enumerate() {
+        // Every file starts with a header
+        if record.first().map(String::as_str) == Some("timestamp") ||
+           (record.len() == 1 && record[0].is_empty()) {
+            continue;
+        }
+
+        let entry = try!(csv_entry(record).map_err(|err| format!("record {}: {}", index + 1, err)));
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn csv_entry(record: Vec<String>) -> Result<HistoryEntry, String> {
+    if record.len() != 13 {
+        return Err(format!("expected 13 fields, found {}", record.len()));
+    }
+
+    let mut fields = record.into_iter();
+    let mut next = || fields.next().unwrap();
+    let optional = |value: String| if value.is_empty() { None } else { Some(value) };
+
+    let timestamp = try!(next().parse().map_err(|_| "invalid timestamp"));
+    let track_uri = next();
+    let track = optional(next());
+    let artist_uri = optional(next());
+    let artist = optional(next());
+    let album_uri = optional(next());
+    let album = optional(next());
+    let duration_ms = match optional(next()) {
+        Some(duration) => Some(try!(duration.parse().map_err(|_| "invalid duration_ms"))),
+        None => None,
+    };
+    let played_ms = try!(next().parse().map_err(|_| "invalid played_ms"));
+    let device = next();
+    let device_ident = next();
+    let context_uri = optional(next());
+    let outcome = try!(ScrobbleOutcome::from_str(&next()).map_err(|()| "invalid outcome"));
+
+    Ok(HistoryEntry {
+        timestamp: timestamp,
+        track_uri: track_uri,
+        track: track,
+        artist_uri: artist_uri,
+        artist: artist,
+        album_uri: album_uri,
+        album: album,
+        duration_ms: duration_ms,
+        played_ms: played_ms,
+        device: device,
+        device_ident: device_ident,
+        context_uri: context_uri,
+        outcome: outcome,
+    })
+}
+
+/// Splits CSV written by `csv_record` into records, undoing the quoting.
+fn csv_records(contents: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => record.push(::std::mem::replace(&mut field, String::new())),
+            '\r' if !quoted => (),
+            '\n' if !quoted => {
+                record.push(::std::mem::replace(&mut field, String::new()));
+                records.push(::std::mem::replace(&mut record, Vec::new()));
+            }
+            c => field.push(c),
+        }
+    }
+
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    records
+}
+
+/// Quotes a field as RFC 4180 asks, when it needs it.
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+impl FromStr for HistoryFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<HistoryFormat, ()> {
+        match s {
+            "jsonl" | "json" => Ok(HistoryFormat::JsonLines),
+            "csv" => Ok(HistoryFormat::Csv),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for ScrobbleOutcome {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ScrobbleOutcome, ()> {
+        match s {
+            "scrobbled" => Ok(ScrobbleOutcome::Scrobbled),
+            "excluded" => Ok(ScrobbleOutcome::Excluded),
+            "too_short" => Ok(ScrobbleOutcome::TooShort),
+            "failed" => Ok(ScrobbleOutcome::Failed),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for ScrobbleOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ScrobbleOutcome::Scrobbled => "scrobbled",
+            ScrobbleOutcome::Excluded => "excluded",
+            ScrobbleOutcome::TooShort => "too_short",
+            ScrobbleOutcome::Failed => "failed",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn entry(timestamp: i64) -> HistoryEntry {
+        HistoryEntry {
+            timestamp: timestamp,
+            track_uri: "spotify:track:4uLU6hMCjMI75M1A2tKUQC".to_owned(),
+            track: Some("Never Gonna Give You Up".to_owned()),
+            artist_uri: Some("spotify:artist:0gxyHStUsqpMadRV0Di1Qt".to_owned()),
+            artist: Some("Rick Astley".to_owned()),
+            album_uri: None,
+            album: Some("Whenever You Need Somebody".to_owned()),
+            duration_ms: Some(213573),
+            played_ms: 200000,
+            device: "Kitchen".to_owned(),
+            device_ident: "0123456789abcdef".to_owned(),
+            context_uri: None,
+            outcome: ScrobbleOutcome::Scrobbled,
+        }
+    }
+
+    fn to_json(entries: &[HistoryEntry]) -> String {
+        serde_json::to_string(entries).unwrap()
+    }
+
+    /// An empty directory of its own for each test.
+    fn test_dir(name: &str) -> PathBuf {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let dir = env::temp_dir().join(format!("history-{}-{}{}", name, now.as_secs(), now.subsec_nanos()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn contents(path: &Path) -> Option<String> {
+        let mut contents = String::new();
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+            Ok(_) => Some(contents),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => panic!("{}: {}", path.display(), err),
+        }
+    }
+
+    fn csv_log(dir: &Path, max_bytes: u64, keep: usize) -> HistoryLog {
+        HistoryLog::new(dir, HistoryConfig {
+            format: HistoryFormat::Csv,
+            max_bytes: max_bytes,
+            keep: keep,
+        })
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let mut plain = entry(1500000000);
+        plain.track = None;
+        plain.duration_ms = None;
+        plain.outcome = ScrobbleOutcome::TooShort;
+
+        let mut tricky = entry(1500000300);
+        tricky.track = Some("Hello, \"World\"".to_owned());
+        tricky.artist = Some("Line one\nline two\r\nline three".to_owned());
+        tricky.album = Some("\"\"".to_owned());
+        tricky.device = "Living room, TV".to_owned();
+        tricky.context_uri = Some("spotify:playlist:37i9dQZF1DXcBWIGoYBM5M".to_owned());
+
+        let entries = vec![plain, tricky];
+        let mut csv = format!("{}\n", CSV_HEADER);
+        for entry in &entries {
+            csv.push_str(&csv_record(entry));
+            csv.push('\n');
+        }
+
+        assert_eq!(to_json(&parse_csv(&csv).unwrap()), to_json(&entries));
+        // Without the trailing newline, as when a write was cut short
+        assert_eq!(to_json(&parse_csv(csv.trim_right_matches('\n')).unwrap()), to_json(&entries));
+    }
+
+    #[test]
+    fn csv_rejects_bad_records() {
+        let csv = format!("{}\n1500000000,spotify:track:4uLU6hMCjMI75M1A2tKUQC\n", CSV_HEADER);
+        assert!(parse_csv(&csv).unwrap_err().contains("expected 13 fields"));
+
+        let record = csv_record(&entry(1500000000)).replace("scrobbled", "lost");
+        assert!(parse_csv(&record).unwrap_err().contains("invalid outcome"));
+    }
+
+    #[test]
+    fn rotate_without_keeping() {
+        let dir = test_dir("keep-0");
+        let log = csv_log(&dir, 1024, 0);
+        log.append(&entry(1500000000)).unwrap();
+
+        log.rotate().unwrap();
+        assert_eq!(contents(log.path()), None);
+        assert_eq!(contents(&log.rotated_path(1)), None);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_keeps_newest() {
+        let dir = test_dir("keep-2");
+        let log = csv_log(&dir, 1024, 2);
+
+        for &(ref path, text) in [(log.path().to_owned(), "current"),
+                              (log.rotated_path(1), "first"),
+                              (log.rotated_path(2), "second")].iter() {
+            File::create(path).unwrap().write_all(text.as_bytes()).unwrap();
+        }
+
+        log.rotate().unwrap();
+        assert_eq!(contents(log.path()), None);
+        assert_eq!(contents(&log.rotated_path(1)), Some("current".to_owned()));
+        assert_eq!(contents(&log.rotated_path(2)), Some("first".to_owned()));
+        assert_eq!(contents(&log.rotated_path(3)), None);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn append_rotates_when_full() {
+        let dir = test_dir("append");
+        // Room for the header and a single entry
+        let log = csv_log(&dir, (CSV_HEADER.len() + csv_record(&entry(0)).len() + 2) as u64 + 16, 2);
+
+        let entries: Vec<HistoryEntry> = (0..4).map(|i| entry(1500000000 + i * 300)).collect();
+        for entry in &entries {
+            log.append(entry).unwrap();
+        }
+
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        assert_eq!(to_json(&log.read().unwrap()), to_json(&entries[1..]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
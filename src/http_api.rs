@@ -0,0 +1,465 @@
+use crypto;
+use futures::{Future, Stream};
+use hyper::server::{Service, Request, Response, Http};
+use hyper::{self, Get, Post, StatusCode};
+use hyper::header::{Authorization, Bearer, ContentType};
+use serde_json::{self, Value};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_core::reactor::Handle;
+use url;
+
+use core::metrics;
+use spirc::ConnectDevice;
+use status::{AuthState, Health, NowPlaying, ScrobblerCommand, ScrobblerStatus};
+
+const DEFAULT_SCROBBLES_LIMIT: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct ApiConfig {
+    pub addr: SocketAddr,
+    /// Required as a bearer token on every request, when set.
+    pub token: Option<String>,
+}
+
+/// A local JSON API to look into and steer the scrobblers of each account.
+///
+/// `GET /status`, `GET /devices` and `GET /scrobbles?limit=N` describe every
+/// account, or the one given with `?account=<id>`. `POST /scrobbling/pause`,
+/// `POST /scrobbling/resume`, `POST /scrobble` (to scrobble the current track
+/// now) and `POST /skip` (to leave it unscrobbled) act on them the same way.
+/// `GET /metrics` exports `core::metrics` to Prometheus.
+///
+/// `GET /health/live` and `GET /health/ready` answer 200 when every account
+/// is live, or ready, and 503 otherwise, see `Health`. They need no token,
+/// for the probes of container orchestrators.
+#[derive(Clone)]
+struct Api(Arc<ApiInner>);
+struct ApiInner {
+    token: Option<String>,
+    accounts: Vec<(String, ScrobblerStatus)>,
+}
+
+type ApiFuture = ::futures::Finished<Response, hyper::Error>;
+
+impl Api {
+    fn is_authorized(&self, request: &Request) -> bool {
+        match self.0.token {
+            Some(ref token) => match request.headers().get::<Authorization<Bearer>>() {
+                Some(auth) => crypto::util::fixed_time_eq(auth.token.as_bytes(), token.as_bytes()),
+                None => false,
+            },
+            None => true,
+        }
+    }
+
+    fn accounts(&self, id: Option<&String>) -> Vec<&(String, ScrobblerStatus)> {
+        self.0.accounts.iter()
+            .filter(|&&(ref label, _)| id.map_or(true, |id| id == label))
+            .collect()
+    }
+
+    fn handle_status(&self, accounts: &[&(String, ScrobblerStatus)]) -> ApiFuture {
+        let accounts: Vec<Value> = accounts.iter().map(|&&(ref label, ref status)| {
+            let (lastfm, lastfm_error) = match status.auth() {
+                AuthState::NotConnected => ("not_connected", None),
+                AuthState::Pending => ("pending", None),
+                AuthState::Authenticated => ("authenticated", None),
+                AuthState::Failed(err) => ("failed", Some(err)),
+            };
+
+            json!({
+                "id": label,
+                "lastfm": lastfm,
+                "lastfm_error": lastfm_error,
+                "scrobbling_paused": (status.is_paused()),
+                "scrobble_in_flight": (status.scrobble_in_flight()),
+                "now_playing": (status.now_playing().as_ref().map(now_playing_json))
+            })
+        }).collect();
+
+        json_response(StatusCode::Ok, json!({ "accounts": accounts }))
+    }
+
+    fn handle_devices(&self, accounts: &[&(String, ScrobblerStatus)]) -> ApiFuture {
+        let accounts: Vec<Value> = accounts.iter().map(|&&(ref label, ref status)| {
+            let devices: Vec<Value> = status.devices().iter().map(device_json).collect();
+            json!({
+                "id": label,
+                "devices": devices
+            })
+        }).collect();
+
+        json_response(StatusCode::Ok, json!({ "accounts": accounts }))
+    }
+
+    fn handle_scrobbles(&self, accounts: &[&(String, ScrobblerStatus)],
+                        params: &BTreeMap<String, String>) -> ApiFuture {
+        let limit = match params.get("limit") {
+            Some(limit) => match limit.parse() {
+                Ok(limit) => limit,
+                Err(_) => return error_response(StatusCode::BadRequest, "invalid limit"),
+            },
+            None => DEFAULT_SCROBBLES_LIMIT,
+        };
+
+        let accounts: Vec<Value> = accounts.iter().map(|&&(ref label, ref status)| {
+            let scrobbles: Vec<Value> = status.recent(limit).iter()
+                .map(|entry| serde_json::to_value(entry).unwrap())
+                .collect();
+            json!({
+                "id": label,
+                "scrobbles": scrobbles
+            })
+        }).collect();
+
+        json_response(StatusCode::Ok, json!({ "accounts": accounts }))
+    }
+
+    fn handle_health<F>(&self, check: F) -> ApiFuture
+        where F: Fn(&Health) -> bool
+    {
+        let mut all_passed = true;
+        let accounts: Vec<Value> = self.0.accounts.iter().map(|&(ref label, ref status)| {
+            let health = status.health();
+            all_passed = all_passed && check(&health);
+            json!({
+                "id": label,
+                "session": (health.session),
+                "subscription": (health.subscription),
+                "lastfm": (health.lastfm),
+                "ready": (health.is_ready()),
+                "live": (health.is_live()),
+                "unready_secs": (health.unready_for.map(|unready_for| unready_for.as_secs())),
+                "disconnected_secs": (health.disconnected_for.map(|disconnected_for| disconnected_for.as_secs()))
+            })
+        }).collect();
+
+        let status = if all_passed { StatusCode::Ok } else { StatusCode::ServiceUnavailable };
+        json_response(status, json!({ "accounts": accounts }))
+    }
+
+    /// Applies `action` to each account, which tells whether it could.
+    fn handle_action<F>(&self, accounts: &[&(String, ScrobblerStatus)], action: F) -> ApiFuture
+        where F: Fn(&ScrobblerStatus) -> bool
+    {
+        let mut all_done = true;
+        let accounts: Vec<Value> = accounts.iter().map(|&&(ref label, ref status)| {
+            let done = action(status);
+            all_done = all_done && done;
+            json!({
+                "id": label,
+                "ok": done
+            })
+        }).collect();
+
+        // Commands only reach accounts connected to Spotify
+        let status = if all_done { StatusCode::Accepted } else { StatusCode::Conflict };
+        json_response(status, json!({ "accounts": accounts }))
+    }
+}
+
+fn now_playing_json(now_playing: &NowPlaying) -> Value {
+    let info = now_playing.info.as_ref();
+    let item = &now_playing.item;
+
+    json!({
+        "track_uri": (item.track.to_string()),
+        "track": (info.map(|info| &info.name)),
+        "artist": (info.map(|info| &info.artist)),
+        "album": (info.map(|info| &info.album)),
+        "duration_ms": (info.and_then(|info| info.duration_ms)),
+        "position_ms": (now_playing.position_ms()),
+        "playing": (now_playing.playing),
+        "started_at": (item.started_at / 1000),
+        "content": (item.content.to_string()),
+        "device": (item.device.name),
+        "device_ident": (item.device.ident),
+        "context_uri": (item.context_uri),
+        "scrobbled": (now_playing.scrobbled),
+        "excluded": (now_playing.excluded)
+    })
+}
+
+fn device_json(known: &ConnectDevice) -> Value {
+    json!({
+        "ident": (known.device.ident),
+        "name": (known.device.name),
+        "device_type": (known.device.device_type.to_string()),
+        "volume": (known.volume),
+        "is_active": (known.is_active),
+        "last_seen_secs": (known.last_seen.elapsed().as_secs())
+    })
+}
+
+fn json_response(status: StatusCode, body: Value) -> ApiFuture {
+    ::futures::finished(Response::new()
+        .with_status(status)
+        .with_header(ContentType::json())
+        .with_body(body.to_string()))
+}
+
+fn error_response(status: StatusCode, message: &str) -> ApiFuture {
+    json_response(status, json!({ "error": message }))
+}
+
+impl Service for Api {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = ApiFuture;
+
+    fn call(&self, request: Request) -> Self::Future {
+        match (request.method(), request.path()) {
+            (&Get, "/health/live") => return self.handle_health(Health::is_live),
+            (&Get, "/health/ready") => return self.handle_health(Health::is_ready),
+            _ => (),
+        }
+
+        if !self.is_authorized(&request) {
+            return error_response(StatusCode::Unauthorized, "missing or wrong token");
+        }
+
+        let params: BTreeMap<String, String> = match request.query() {
+            Some(query) => url::form_urlencoded::parse(query.as_bytes()).into_owned().collect(),
+            None => BTreeMap::new(),
+        };
+
+        if request.path() == "/metrics" {
+            return match *request.method() {
+                Get => ::futures::finished(Response::new()
+                    .with_header(ContentType::plaintext())
+                    .with_body(metrics::render())),
+                _ => error_response(StatusCode::MethodNotAllowed, "method not allowed"),
+            };
+        }
+
+        let accounts = self.accounts(params.get("account"));
+        if accounts.is_empty() {
+            return error_response(StatusCode::NotFound, "unknown account");
+        }
+
+        debug!("{} {}", request.method(), request.path());
+
+        match (request.method(), request.path()) {
+            (&Get, "/status") => self.handle_status(&accounts),
+            (&Get, "/devices") => self.handle_devices(&accounts),
+            (&Get, "/scrobbles") => self.handle_scrobbles(&accounts, &params),
+            (&Post, "/scrobbling/pause") => self.handle_action(&accounts, |status| {
+                status.set_paused(true);
+                true
+            }),
+            (&Post, "/scrobbling/resume") => self.handle_action(&accounts, |status| {
+                status.set_paused(false);
+                true
+            }),
+            (&Post, "/scrobble") => {
+                self.handle_action(&accounts, |status| status.send(ScrobblerCommand::ForceScrobble))
+            }
+            (&Post, "/skip") => self.handle_action(&accounts, |status| status.send(ScrobblerCommand::Skip)),
+            (_, "/status") | (_, "/devices") | (_, "/scrobbles") | (_, "/scrobbling/pause") |
+            (_, "/scrobbling/resume") | (_, "/scrobble") | (_, "/skip") => {
+                error_response(StatusCode::MethodNotAllowed, "method not allowed")
+            }
+            _ => error_response(StatusCode::NotFound, "not found"),
+        }
+    }
+}
+
+/// Serves the API for `accounts`, by id, on the reactor behind `handle`.
+pub fn serve(handle: &Handle, config: ApiConfig, accounts: Vec<(String, ScrobblerStatus)>)
+    -> io::Result<SocketAddr>
+{
+    let api = Api(Arc::new(ApiInner {
+        token: config.token,
+        accounts: accounts,
+    }));
+
+    let serve = {
+        let http = Http::new();
+        try!(http.serve_addr_handle(&config.addr, handle, move || Ok(api.clone()))
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err)))
+    };
+    let addr = serve.incoming_ref().local_addr();
+    let server_future = {
+        let handle = handle.clone();
+        serve.for_each(move |connection| {
+            handle.spawn(connection.then(|_| Ok(())));
+            Ok(())
+        })
+        .then(|_| Ok(()))
+    };
+    handle.spawn(server_future);
+
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::sync::mpsc;
+    use hyper::{Delete, Method};
+
+    use history::{HistoryEntry, ScrobbleOutcome};
+
+    fn api(token: Option<&str>) -> Api {
+        Api(Arc::new(ApiInner {
+            token: token.map(str::to_owned),
+            accounts: vec![
+                ("alice".to_owned(), ScrobblerStatus::new()),
+                ("bob".to_owned(), ScrobblerStatus::new()),
+            ],
+        }))
+    }
+
+    fn account_status(api: &Api, account: &str) -> ScrobblerStatus {
+        api.0.accounts.iter().find(|&&(ref label, _)| label == account).unwrap().1.clone()
+    }
+
+    fn call(api: &Api, method: Method, uri: &str, token: Option<&str>) -> (StatusCode, Value) {
+        let mut request = Request::new(method, uri.parse().unwrap());
+        if let Some(token) = token {
+            request.headers_mut().set(Authorization(Bearer { token: token.to_owned() }));
+        }
+
+        let response = api.call(request).wait().unwrap();
+        let status = response.status();
+        let body = response.body().concat2().wait().unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn ids(body: &Value) -> Vec<String> {
+        body["accounts"].as_array().unwrap().iter()
+            .map(|account| account["id"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    fn entry(timestamp: i64) -> HistoryEntry {
+        HistoryEntry {
+            timestamp: timestamp,
+            track_uri: "spotify:track:4uLU6hMCjMI75M1A2tKUQC".to_owned(),
+            track: Some("Never Gonna Give You Up".to_owned()),
+            artist_uri: None,
+            artist: Some("Rick Astley".to_owned()),
+            album_uri: None,
+            album: None,
+            duration_ms: Some(213573),
+            played_ms: 200000,
+            device: "Kitchen".to_owned(),
+            device_ident: "0123456789abcdef".to_owned(),
+            context_uri: None,
+            outcome: ScrobbleOutcome::Scrobbled,
+        }
+    }
+
+    #[test]
+    fn requires_token() {
+        let api = api(Some("secret"));
+        assert_eq!(call(&api, Get, "/status", None).0, StatusCode::Unauthorized);
+        assert_eq!(call(&api, Get, "/status", Some("wrong")).0, StatusCode::Unauthorized);
+        assert_eq!(call(&api, Get, "/metrics", Some("secre")).0, StatusCode::Unauthorized);
+        assert_eq!(call(&api, Post, "/skip", None).0, StatusCode::Unauthorized);
+        assert_eq!(call(&api, Get, "/status", Some("secret")).0, StatusCode::Ok);
+        assert_eq!(call(&api, Get, "/metrics", Some("secret")).0, StatusCode::Ok);
+    }
+
+    #[test]
+    fn health_needs_no_token() {
+        let api = api(Some("secret"));
+
+        let (status, body) = call(&api, Get, "/health/live", None);
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(ids(&body), vec!["alice", "bob"]);
+
+        // Reachable, but nothing is connected yet
+        let (status, body) = call(&api, Get, "/health/ready", None);
+        assert_eq!(status, StatusCode::ServiceUnavailable);
+        assert_eq!(body["accounts"][0]["ready"], Value::Bool(false));
+    }
+
+    #[test]
+    fn filters_accounts() {
+        let api = api(None);
+
+        let (status, body) = call(&api, Get, "/status", None);
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(ids(&body), vec!["alice", "bob"]);
+
+        let (status, body) = call(&api, Get, "/devices?account=bob", None);
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(ids(&body), vec!["bob"]);
+
+        assert_eq!(call(&api, Get, "/status?account=carol", None).0, StatusCode::NotFound);
+        assert_eq!(call(&api, Post, "/scrobbling/pause?account=carol", None).0, StatusCode::NotFound);
+
+        assert_eq!(call(&api, Post, "/scrobbling/pause?account=alice", None).0, StatusCode::Accepted);
+        assert!(account_status(&api, "alice").is_paused());
+        assert!(!account_status(&api, "bob").is_paused());
+    }
+
+    #[test]
+    fn limits_scrobbles() {
+        let api = api(None);
+        for timestamp in 1..4 {
+            account_status(&api, "alice").track_ended(entry(timestamp));
+        }
+
+        let scrobbles = |body: &Value| -> Vec<i64> {
+            body["accounts"][0]["scrobbles"].as_array().unwrap().iter()
+                .map(|scrobble| scrobble["timestamp"].as_i64().unwrap())
+                .collect()
+        };
+
+        let (status, body) = call(&api, Get, "/scrobbles?account=alice", None);
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(scrobbles(&body), vec![3, 2, 1]);
+
+        let (status, body) = call(&api, Get, "/scrobbles?account=alice&limit=2", None);
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(scrobbles(&body), vec![3, 2]);
+
+        for limit in &["", "two", "-1", "1.5"] {
+            let (status, body) = call(&api, Get, &format!("/scrobbles?limit={}", limit), None);
+            assert_eq!(status, StatusCode::BadRequest, "limit {:?}", limit);
+            assert_eq!(body["error"], Value::String("invalid limit".to_owned()));
+        }
+    }
+
+    #[test]
+    fn routes_methods() {
+        let api = api(None);
+        assert_eq!(call(&api, Get, "/nowhere", None).0, StatusCode::NotFound);
+        assert_eq!(call(&api, Post, "/nowhere", None).0, StatusCode::NotFound);
+        assert_eq!(call(&api, Post, "/status", None).0, StatusCode::MethodNotAllowed);
+        assert_eq!(call(&api, Delete, "/scrobbles", None).0, StatusCode::MethodNotAllowed);
+        assert_eq!(call(&api, Get, "/skip", None).0, StatusCode::MethodNotAllowed);
+        assert_eq!(call(&api, Get, "/scrobbling/resume", None).0, StatusCode::MethodNotAllowed);
+        assert_eq!(call(&api, Post, "/metrics", None).0, StatusCode::MethodNotAllowed);
+
+        // Only GET skips the token
+        let api = self::api(Some("secret"));
+        assert_eq!(call(&api, Post, "/health/live", None).0, StatusCode::Unauthorized);
+    }
+
+    #[test]
+    fn commands_need_a_scrobbler() {
+        let api = api(None);
+        assert_eq!(call(&api, Post, "/scrobble", None).0, StatusCode::Conflict);
+        assert_eq!(call(&api, Post, "/skip", None).0, StatusCode::Conflict);
+
+        let (commands, received) = mpsc::unbounded();
+        account_status(&api, "alice").attach(commands);
+
+        // Bob has no scrobbler yet
+        let (status, body) = call(&api, Post, "/skip", None);
+        assert_eq!(status, StatusCode::Conflict);
+        assert_eq!(body["accounts"][0]["ok"], Value::Bool(true));
+        assert_eq!(body["accounts"][1]["ok"], Value::Bool(false));
+
+        assert_eq!(call(&api, Post, "/scrobble?account=alice", None).0, StatusCode::Accepted);
+        let received: Vec<ScrobblerCommand> = received.take(2).collect().wait().unwrap();
+        assert_eq!(received, vec![ScrobblerCommand::Skip, ScrobblerCommand::ForceScrobble]);
+    }
+}
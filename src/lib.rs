@@ -9,20 +9,41 @@
 
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
 
+extern crate base64;
+extern crate crypto;
 extern crate futures;
+extern crate hyper;
+extern crate mdns;
 extern crate num_bigint;
 extern crate protobuf;
 extern crate rand;
+extern crate reqwest;
+#[cfg(feature = "stats")]
+extern crate rusqlite;
 extern crate rustfm_scrobble;
 extern crate tokio_core;
+extern crate toml;
+extern crate url;
 
 pub extern crate librespot_core as core;
 pub extern crate librespot_protocol as protocol;
 pub extern crate librespot_metadata as metadata;
 
+pub mod backfill;
+pub mod config_file;
+pub mod discovery;
+pub mod history;
+pub mod http_api;
 pub mod keymaster;
+pub mod playback_events;
 pub mod scrobbler;
+pub mod spirc_state;
+#[cfg(feature = "stats")]
+pub mod stats;
+pub mod status;
+pub mod webhooks;
 
 include!(concat!(env!("OUT_DIR"), "/lib.rs"));
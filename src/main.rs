@@ -5,32 +5,53 @@
 extern crate env_logger;
 extern crate futures;
 extern crate getopts;
+extern crate hyper;
 extern crate librespot;
+extern crate reqwest;
 extern crate tokio_core;
 extern crate tokio_signal;
 
 use env_logger::LogBuilder;
 use futures::{Future, Async, Poll, Stream};
+use hyper::Uri;
+use std::cmp;
 use std::env;
+use std::fmt;
 use std::io::{self, stderr, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::PathBuf;
 use std::process::exit;
 use std::str::FromStr;
-use tokio_core::reactor::{Handle, Core};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Core, Interval, Timeout};
 use tokio_core::io::IoStream;
-use std::mem;
 
 use librespot::core::authentication::{get_credentials, Credentials};
-use librespot::core::cache::Cache;
-use librespot::core::config::{DeviceType, SessionConfig, ConnectConfig};
+use librespot::core::cache::{Cache, CacheKey};
+use librespot::core::config::{CommandPolicy, DeviceType, SessionConfig, ConnectConfig};
+use librespot::core::metrics::{Kind, Metric};
+use librespot::core::secrets;
 use librespot::core::session::Session;
+use librespot::core::util::now_ms;
 use librespot::core::version;
 
-use librespot::scrobbler::ScrobblerConfig;
+use librespot::config_file::{AccountSection, ConfigError, ConfigFile, WebhookSection};
+use librespot::discovery::{discovery, DiscoveryStream};
+use librespot::backfill::{self, AckLedger, BackfillOptions, BackfillReport, Destination, LastFm, ListenBrainz};
+use librespot::history::{HistoryConfig, HistoryFormat, HistoryLog, PlayStore};
+use librespot::http_api::{self, ApiConfig};
+use librespot::scrobbler::{ScrobbledPlays, Scrobbler, ScrobblerConfig, DEFAULT_EXCLUDED_CONTENT};
 use librespot::spirc::{Spirc, SpircTask};
+use librespot::spirc_state::ContentType;
+use librespot::status::{ScrobblerStatus, PROBE_INTERVAL_SECS};
+use librespot::webhooks::{WebhookConfig, WebhookEvent, Webhooks};
+#[cfg(feature = "stats")]
+use librespot::stats::{Ranked, StatsStore};
 
 fn usage(program: &str, opts: &getopts::Options) -> String {
-    let brief = format!("Usage: {} [options]", program);
+    let brief = format!("Usage: {0} [options]\n       {0} backfill [options]\n       {0} stats [options]",
+                        program);
     opts.usage(&brief)
 }
 
@@ -57,25 +78,94 @@ fn setup_logging(verbose: bool) {
 }
 
 struct Setup {
+    accounts: Vec<AccountSetup>,
+    /// The accounts for the `backfill` and `stats` commands, instead of
+    /// `accounts`.
+    offline_accounts: Vec<OfflineAccount>,
+    command: Command,
+    http: Option<ApiConfig>,
+}
+
+enum Command {
+    Run,
+    Backfill(BackfillSetup),
+    Stats(StatsSetup),
+}
+
+/// The `backfill` command, replaying the play history instead of running.
+struct BackfillSetup {
+    destination: BackfillDestination,
+    options: BackfillOptions,
+}
+
+enum BackfillDestination {
+    LastFm,
+    ListenBrainz { token: String },
+}
+
+/// The `stats` command, printing reports from the statistics database.
+struct StatsSetup {
+    from: Option<i64>,
+    until: Option<i64>,
+    top: i64,
+}
+
+struct AccountSetup {
+    label: String,
     cache: Option<Cache>,
     session_config: SessionConfig,
     connect_config: ConnectConfig,
     credentials: Option<Credentials>,
+    enable_discovery: bool,
     scrobbler_config: ScrobblerConfig
 }
 
+/// An account as the `backfill` and `stats` commands see it, which only
+/// read what was recorded in the cache directory and never sign in to Spotify.
+struct OfflineAccount {
+    label: String,
+    cache: Option<Cache>,
+    history: Option<HistoryLog>,
+    /// Only resolved for backfills to Last.fm.
+    lastfm: Option<ScrobblerConfig>,
+}
+
 fn setup(args: &[String]) -> Setup {
     let mut opts = getopts::Options::new();
-    opts.optopt("c", "cache", "Path to a directory where files will be cached.", "CACHE")
+    opts.optopt("", "config", "Path to a TOML configuration file", "CONFIG")
+        .optopt("c", "cache", "Path to a directory where files will be cached.", "CACHE")
+        .optopt("", "cache-passphrase", "Passphrase to encrypt cached credentials with, or file:PATH / cmd:COMMAND", "PASSPHRASE")
+        .optopt("", "history", "Record every play in the cache directory, as jsonl or csv", "FORMAT")
+        .optflag("", "stats", "Keep listening statistics in the cache directory")
         .optopt("n", "name", "Device name (defaults to Scrobbler)", "NAME")
         .optopt("", "device-type", "Displayed device type", "DEVICE_TYPE")
+        .optopt("", "proxy", "HTTP proxy to connect to Spotify through", "PROXY")
+        .optopt("", "http", "Serve the status and control API on this address, e.g. 127.0.0.1:8080", "ADDR")
+        .optopt("", "http-token", "Bearer token the API requires, or file:PATH / cmd:COMMAND", "TOKEN")
+        .optmulti("", "webhook", "Notify this URL of now-playing and scrobble events", "URL")
+        .optflag("", "healthcheck", "Ask the scrobbler serving the API whether it is live, then exit")
         .optflag("v", "verbose", "Enable verbose output")
+        .optflag("", "enable-discovery", "Let Spotify apps on the local network sign the scrobbler in")
+        .optflag("", "hidden", "Hide the device from device pickers, observing playback only")
+        .optflag("", "allow-rename", "Let Spotify apps rename the device")
+        .optopt("", "playback-commands", "What to do when picked as output: reject (default) or hand-off", "POLICY")
         .optopt("", "spotify-username", "Username to sign in with", "USERNAME")
-        .optopt("", "spotify-password", "Password", "PASSWORD")
+        .optopt("", "spotify-password", "Password, or file:PATH / cmd:COMMAND to read it from", "PASSWORD")
         .optopt("", "lastfm-username", "Last.fm Username", "LASTFM_USERNAME")
-        .optopt("", "lastfm-password", "Last.fm Password", "LASTFM_PASSWORD")
+        .optopt("", "lastfm-password", "Last.fm Password, or file:PATH / cmd:COMMAND", "LASTFM_PASSWORD")
         .optopt("", "lastfm-api-key", "Last.fm API Key", "API_KEY")
-        .optopt("", "lastfm-api-secret", "Last.fm API Secret", "SECRET");
+        .optopt("", "lastfm-api-secret", "Last.fm API Secret, or file:PATH / cmd:COMMAND", "SECRET")
+        .optmulti("", "exclude-content", "Content type not to scrobble (episode, audiobook, both by default)", "TYPE")
+        .optmulti("", "include-device", "Only scrobble plays on this Connect device", "DEVICE")
+        .optmulti("", "exclude-device", "Don't scrobble plays on this Connect device", "DEVICE")
+        .optopt("", "to", "backfill: Service to replay plays to, lastfm (default) or listenbrainz", "SERVICE")
+        .optopt("", "from", "backfill, stats: First day to include", "YYYY-MM-DD")
+        .optopt("", "until", "backfill, stats: Last day to include", "YYYY-MM-DD")
+        .optopt("", "listenbrainz-token", "backfill: ListenBrainz user token, or file:PATH / cmd:COMMAND", "TOKEN")
+        .optflag("", "include-scrobbled", "backfill: Also replay plays scrobbled to Last.fm already")
+        .optflag("", "dry-run", "backfill: Only show how many plays would be replayed")
+        .optopt("", "period", "stats: day, week (default), month, year or all, when not given --from", "PERIOD")
+        .optopt("", "top", "stats: Entries in each report (defaults to 10)", "N");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -85,7 +175,16 @@ fn setup(args: &[String]) -> Setup {
         }
     };
 
-    let verbose = matches.opt_present("verbose");
+    let file = match matches.opt_str("config").or_else(|| env::var("SCROBBLER_CONFIG").ok()) {
+        Some(path) => ConfigFile::load(path).unwrap_or_else(|err| fail(err)),
+        None => ConfigFile::default(),
+    };
+
+    if matches.opt_present("healthcheck") {
+        run_healthcheck(setting(&matches, "http", file.http.listen.clone(), Some("SCROBBLER_HTTP")));
+    }
+
+    let verbose = matches.opt_present("verbose") || file.verbose.unwrap_or(false);
     setup_logging(verbose);
 
     info!("librespot {} ({}). Built on {}. Build ID: {}",
@@ -94,115 +193,807 @@ fn setup(args: &[String]) -> Setup {
              version::short_now(),
              version::build_id());
 
-    let name = matches.opt_str("name").unwrap_or(String::from("Scrobbler"));
+    resolve(&matches, file).unwrap_or_else(|err| fail(err))
+}
+
+// How long the health check waits for an answer
+const HEALTHCHECK_TIMEOUT_SECS: u64 = 5;
+
+/// Exits with 0 if the scrobbler serving the API on `listen` is live, for
+/// Docker's HEALTHCHECK.
+fn run_healthcheck(listen: Option<String>) -> ! {
+    let listen = match listen {
+        Some(listen) => listen,
+        None => fail("--healthcheck needs the address the API listens on, see --http"),
+    };
+    let addr: SocketAddr = match listen.parse() {
+        Ok(addr) => addr,
+        Err(_) => fail(ConfigError::invalid("http.listen", listen, "expected host:port")),
+    };
+
+    // Reach a wildcard address through the loopback interface
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+        ip => ip,
+    };
+    let url = format!("http://{}/health/live", SocketAddr::new(ip, addr.port()));
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(HEALTHCHECK_TIMEOUT_SECS))
+        .build()
+        .unwrap_or_else(|err| fail(err));
+
+    match client.get(&url).send() {
+        Ok(response) => {
+            let healthy = response.status().is_success();
+            println!("{}: {}", url, response.status());
+            exit(if healthy { 0 } else { 1 })
+        }
+        Err(err) => fail(format!("{}: {}", url, err)),
+    }
+}
+
+fn fail<E: fmt::Display, T>(err: E) -> T {
+    writeln!(stderr(), "error: {}", err).unwrap();
+    exit(1);
+}
+
+/// Looks a setting up on the command line first, then in the config file,
+/// then in the environment.
+fn setting(matches: &getopts::Matches, flag: &str, value: Option<String>, env_var: Option<&str>)
+    -> Option<String>
+{
+    matches.opt_str(flag)
+        .or(value)
+        .or_else(|| env_var.and_then(|var| env::var(var).ok()))
+}
+
+/// A setting every account needs, with the names it goes by in each place
+/// it can be given.
+struct Required {
+    key: &'static str,
+    account_key: &'static str,
+    flag: &'static str,
+    env: &'static str,
+}
+
+const LASTFM_API_KEY: Required = Required {
+    key: "scrobbler.lastfm_api_key",
+    account_key: "lastfm_api_key",
+    flag: "lastfm-api-key",
+    env: "LASTFM_API_KEY",
+};
+
+const LASTFM_API_SECRET: Required = Required {
+    key: "scrobbler.lastfm_api_secret",
+    account_key: "lastfm_api_secret",
+    flag: "lastfm-api-secret",
+    env: "LASTFM_API_SECRET",
+};
+
+const LASTFM_USERNAME: Required = Required {
+    key: "scrobbler.lastfm_username",
+    account_key: "lastfm_username",
+    flag: "lastfm-username",
+    env: "LASTFM_USERNAME",
+};
+
+const LASTFM_PASSWORD: Required = Required {
+    key: "scrobbler.lastfm_password",
+    account_key: "lastfm_password",
+    flag: "lastfm-password",
+    env: "LASTFM_PASSWORD",
+};
+
+const SPOTIFY_USERNAME: Required = Required {
+    key: "session.username",
+    account_key: "spotify_username",
+    flag: "spotify-username",
+    env: "SPOTIFY_USERNAME",
+};
+
+/// `account` is the id of the `[[account]]` table the value was looked up
+/// in, if any.
+fn require(value: Option<String>, account: Option<&str>, setting: &Required)
+    -> Result<String, ConfigError>
+{
+    value.ok_or_else(|| match account {
+        Some(account) => ConfigError::MissingAccountSetting {
+            account: account.to_owned(),
+            key: setting.account_key,
+        },
+        None => ConfigError::Missing {
+            key: setting.key,
+            flag: setting.flag,
+            env: Some(setting.env),
+        },
+    })
+}
+
+fn secret(value: String, account: Option<&str>, setting: &Required) -> Result<String, ConfigError> {
+    let key = if account.is_some() { setting.account_key } else { setting.key };
+    secrets::resolve(&value).map_err(|err| ConfigError::Secret(key, err))
+}
+
+fn setting_list(matches: &getopts::Matches, flag: &str, value: Option<Vec<String>>) -> Vec<String> {
+    let values = matches.opt_strs(flag);
+    if !values.is_empty() {
+        values
+    } else {
+        value.unwrap_or_default()
+    }
+}
+
+fn parse_proxy(url: String) -> Result<Uri, ConfigError> {
+    let uri = match url.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(err) => return Err(ConfigError::invalid("proxy.url", url, err.to_string())),
+    };
+
+    if uri.scheme() != Some("http") || uri.host().is_none() {
+        return Err(ConfigError::invalid("proxy.url", url, "expected http://host:port"));
+    }
+
+    Ok(uri)
+}
+
+// Rotation of the play history, unless configured otherwise
+const HISTORY_MAX_SIZE_MB: u64 = 10;
+const HISTORY_KEEP: usize = 5;
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+fn resolve(matches: &getopts::Matches, file: ConfigFile) -> Result<Setup, ConfigError> {
+    let proxy = match setting(matches, "proxy", file.proxy.url, None) {
+        Some(url) => Some(try!(parse_proxy(url))),
+        None => None,
+    };
+
+    let cache_location = setting(matches, "cache", file.cache.path, None).map(PathBuf::from);
+    let cache_passphrase = setting(matches, "cache-passphrase", file.cache.passphrase,
+                                   Some("SCROBBLER_CACHE_PASSPHRASE"));
+    let cache_key = match cache_passphrase {
+        Some(passphrase) => {
+            let passphrase = try!(secrets::resolve(&passphrase)
+                .map_err(|err| ConfigError::Secret("cache.passphrase", err)));
+            Some(CacheKey::from_passphrase(&passphrase))
+        }
+        None => CacheKey::from_machine_id(),
+    };
+
+    if cache_location.is_some() && cache_key.is_none() {
+        warn!("No cache passphrase given and no machine id found, credentials will be cached unencrypted");
+    }
+
+    let history = match setting(matches, "history", file.history.format, None) {
+        Some(format) => {
+            let format = match HistoryFormat::from_str(&format) {
+                Ok(format) => format,
+                Err(()) => {
+                    return Err(ConfigError::invalid("history.format", format, "expected jsonl or csv"));
+                }
+            };
+
+            if cache_location.is_none() {
+                warn!("The play history is kept in the cache directory, set one with --cache");
+            }
+
+            Some(HistoryConfig {
+                format: format,
+                max_bytes: file.history.max_size_mb.unwrap_or(HISTORY_MAX_SIZE_MB) * 1024 * 1024,
+                keep: file.history.keep.unwrap_or(HISTORY_KEEP),
+            })
+        }
+        None => None,
+    };
+
+    let scrobbler = file.scrobbler;
+    // Unlike the other lists, an empty one in the file means something, that
+    // nothing is excluded, so it isn't replaced by the default
+    let exclude_content = matches.opt_strs("exclude-content");
+    let defaults = AccountSection {
+        id: None,
+        name: setting(matches, "name", file.connect.name, None),
+        device_type: setting(matches, "device-type", file.connect.device_type, None),
+        discovery: Some(matches.opt_present("enable-discovery") ||
+                        file.connect.discovery.unwrap_or(false)),
+        hidden: Some(matches.opt_present("hidden") || file.connect.hidden.unwrap_or(false)),
+        rename: Some(matches.opt_present("allow-rename") || file.connect.rename.unwrap_or(false)),
+        playback_commands: setting(matches, "playback-commands", file.connect.playback_commands, None),
+        spotify_username: setting(matches, "spotify-username", file.session.username,
+                                  Some("SPOTIFY_USERNAME")),
+        spotify_password: setting(matches, "spotify-password", file.session.password,
+                                  Some("SPOTIFY_PASSWORD")),
+        lastfm_username: setting(matches, "lastfm-username", scrobbler.lastfm_username,
+                                 Some("LASTFM_USERNAME")),
+        lastfm_password: setting(matches, "lastfm-password", scrobbler.lastfm_password,
+                                 Some("LASTFM_PASSWORD")),
+        lastfm_api_key: setting(matches, "lastfm-api-key", scrobbler.lastfm_api_key,
+                                Some("LASTFM_API_KEY")),
+        lastfm_api_secret: setting(matches, "lastfm-api-secret", scrobbler.lastfm_api_secret,
+                                   Some("LASTFM_API_SECRET")),
+        exclude_content: if exclude_content.is_empty() {
+            scrobbler.exclude_content
+        } else {
+            Some(exclude_content)
+        },
+        include_devices: Some(setting_list(matches, "include-device", scrobbler.include_devices)),
+        exclude_devices: Some(setting_list(matches, "exclude-device", scrobbler.exclude_devices)),
+    };
+
+    let command = match matches.free.first().map(String::as_str) {
+        Some("backfill") => Command::Backfill(try!(resolve_backfill(matches))),
+        Some("stats") => Command::Stats(try!(resolve_stats(matches))),
+        Some(command) => {
+            return Err(ConfigError::invalid("command", command, "expected backfill or stats"));
+        }
+        None => Command::Run,
+    };
+
+    let http = match setting(matches, "http", file.http.listen, Some("SCROBBLER_HTTP")) {
+        Some(listen) => {
+            let addr: SocketAddr = try!(listen.parse()
+                .map_err(|_| ConfigError::invalid("http.listen", listen, "expected host:port")));
+            let token = match setting(matches, "http-token", file.http.token, Some("SCROBBLER_HTTP_TOKEN")) {
+                Some(token) => Some(try!(secrets::resolve(&token)
+                    .map_err(|err| ConfigError::Secret("http.token", err)))),
+                None => None,
+            };
+
+            Some(ApiConfig {
+                addr: addr,
+                token: token,
+            })
+        }
+        None => None,
+    };
+
+    let webhooks = match command {
+        Command::Run => {
+            let mut sections = file.webhook;
+            sections.extend(matches.opt_strs("webhook").into_iter().map(|url| WebhookSection {
+                url: url,
+                ..WebhookSection::default()
+            }));
+            Webhooks::start(try!(resolve_webhooks(sections)))
+        }
+        _ => Webhooks::none(),
+    };
+
+    let stats = matches.opt_present("stats") || file.stats.enabled.unwrap_or(false);
+    if stats && cache_location.is_none() {
+        warn!("Listening statistics are kept in the cache directory, set one with --cache");
+    }
+
+    let mut sections = Vec::new();
+    if file.account.is_empty() {
+        let cache = cache_location.and_then(|location| open_cache(location, cache_key));
+        sections.push((defaults, None, cache));
+    } else {
+        for (index, account) in file.account.into_iter().enumerate() {
+            let id = match account.id.clone().or_else(|| account.spotify_username.clone()) {
+                Some(id) => id,
+                None => {
+                    return Err(ConfigError::MissingAccountSetting {
+                        account: format!("#{}", index + 1),
+                        key: "id",
+                    });
+                }
+            };
+
+            if !id.chars().all(|c| c.is_alphanumeric() || c == '.' || c == '-' || c == '_') ||
+               id.starts_with('.') {
+                return Err(ConfigError::invalid("account.id", id,
+                                                "only letters, digits, '.', '-' and '_' are allowed"));
+            }
+
+            let cache = cache_location.as_ref()
+                .and_then(|location| open_cache(location.join(&id), cache_key.clone()));
+            sections.push((account.inherit(&defaults), Some(id), cache));
+        }
+    }
+
+    // Whether an offline command needs the Last.fm settings, as backfills
+    // to Last.fm sign in there
+    let offline_lastfm = match command {
+        Command::Run => None,
+        Command::Backfill(ref backfill) => Some(match backfill.destination {
+            BackfillDestination::LastFm => true,
+            BackfillDestination::ListenBrainz { .. } => false,
+        }),
+        Command::Stats(_) => Some(false),
+    };
+
+    let mut accounts: Vec<AccountSetup> = Vec::new();
+    let mut offline_accounts: Vec<OfflineAccount> = Vec::new();
+    for (account, id, cache) in sections {
+        let id = id.as_ref().map(String::as_str);
+
+        match offline_lastfm {
+            Some(lastfm) => {
+                let account = try!(resolve_offline_account(account, id, cache, history, lastfm));
+
+                if offline_accounts.iter().any(|other| other.label == account.label) {
+                    return Err(ConfigError::DuplicateAccount(account.label));
+                }
+
+                offline_accounts.push(account);
+            }
+            None => {
+                let account = try!(resolve_account(account, id, cache, proxy.clone(), history, stats, &webhooks));
+
+                if accounts.iter().any(|other| other.label == account.label ||
+                                               other.connect_config.name == account.connect_config.name) {
+                    return Err(ConfigError::DuplicateAccount(account.label));
+                }
+
+                accounts.push(account);
+            }
+        }
+    }
+
+    Ok(Setup {
+        accounts: accounts,
+        offline_accounts: offline_accounts,
+        command: command,
+        http: http,
+    })
+}
+
+/// The days given with `--from` and `--until`, in seconds since the epoch,
+/// including the whole of the last day.
+fn date_range(matches: &getopts::Matches) -> Result<(Option<i64>, Option<i64>), ConfigError> {
+    let date = |flag: &'static str| match matches.opt_str(flag) {
+        Some(date) => match backfill::parse_date(&date) {
+            Some(time) => Ok(Some(time)),
+            None => Err(ConfigError::invalid(flag, date, "expected YYYY-MM-DD")),
+        },
+        None => Ok(None),
+    };
+
+    let from = try!(date("from"));
+    let until = try!(date("until")).map(|until| until + DAY_SECS);
+
+    Ok((from, until))
+}
+
+fn resolve_backfill(matches: &getopts::Matches) -> Result<BackfillSetup, ConfigError> {
+    let (from, until) = try!(date_range(matches));
+
+    let destination = match matches.opt_str("to") {
+        None => BackfillDestination::LastFm,
+        Some(ref service) if service == "lastfm" => BackfillDestination::LastFm,
+        Some(ref service) if service == "listenbrainz" => {
+            let token = match setting(matches, "listenbrainz-token", None, Some("LISTENBRAINZ_TOKEN")) {
+                Some(token) => try!(secrets::resolve(&token)
+                    .map_err(|err| ConfigError::Secret("listenbrainz-token", err))),
+                None => {
+                    return Err(ConfigError::Missing {
+                        key: "listenbrainz-token",
+                        flag: "listenbrainz-token",
+                        env: Some("LISTENBRAINZ_TOKEN"),
+                    });
+                }
+            };
+            BackfillDestination::ListenBrainz { token: token }
+        }
+        Some(service) => {
+            return Err(ConfigError::invalid("to", service, "expected lastfm or listenbrainz"));
+        }
+    };
+
+    Ok(BackfillSetup {
+        destination: destination,
+        options: BackfillOptions {
+            from: from,
+            until: until,
+            include_scrobbled: matches.opt_present("include-scrobbled"),
+            dry_run: matches.opt_present("dry-run"),
+        },
+    })
+}
+
+fn resolve_stats(matches: &getopts::Matches) -> Result<StatsSetup, ConfigError> {
+    let (from, until) = try!(date_range(matches));
+
+    let period_days = match matches.opt_str("period") {
+        None => Some(7),
+        Some(period) => match period.as_str() {
+            "day" => Some(1),
+            "week" => Some(7),
+            "month" => Some(30),
+            "year" => Some(365),
+            "all" => None,
+            _ => return Err(ConfigError::invalid("period", period, "expected day, week, month, year or all")),
+        },
+    };
+    let from = from.or_else(|| period_days.map(|days| now_ms() / 1000 - days * DAY_SECS));
+
+    let top = match matches.opt_str("top") {
+        Some(top) => match top.parse() {
+            Ok(top) if top > 0 => top,
+            _ => return Err(ConfigError::invalid("top", top, "expected a positive number")),
+        },
+        None => 10,
+    };
+
+    Ok(StatsSetup {
+        from: from,
+        until: until,
+        top: top,
+    })
+}
+
+/// Runs without a cache, rather than not at all, when the cache directory
+/// can't be created.
+fn open_cache(location: PathBuf, key: Option<CacheKey>) -> Option<Cache> {
     let use_audio_cache = false;
 
-    let cache = matches.opt_str("c").map(|cache_location| {
-        Cache::new(PathBuf::from(cache_location), use_audio_cache)
+    let cache = match Cache::new(location.clone(), use_audio_cache) {
+        Ok(cache) => cache,
+        Err(err) => {
+            error!("Cannot use cache directory {}: {}", location.display(), err);
+            return None;
+        }
+    };
+
+    match key {
+        Some(key) => Some(cache.encrypt_with(key)),
+        None => Some(cache),
+    }
+}
+
+fn cached_credentials(cache: Option<&Cache>) -> Option<Credentials> {
+    match cache.map(Cache::credentials) {
+        Some(Ok(credentials)) => credentials,
+        Some(Err(err)) => {
+            warn!("Ignoring cached credentials: {}", err);
+            None
+        }
+        None => None,
+    }
+}
+
+fn resolve_webhooks(sections: Vec<WebhookSection>) -> Result<Vec<WebhookConfig>, ConfigError> {
+    let mut hooks = Vec::new();
+    for section in sections {
+        let valid = match section.url.parse::<Uri>() {
+            Ok(uri) => uri.scheme() == Some("http") || uri.scheme() == Some("https"),
+            Err(_) => false,
+        };
+        if !valid {
+            return Err(ConfigError::invalid("webhook.url", section.url, "expected an http(s) URL"));
+        }
+
+        let mut events = Vec::new();
+        for event in section.events.unwrap_or_default() {
+            match WebhookEvent::from_str(&event) {
+                Ok(event) => events.push(event),
+                Err(()) => {
+                    return Err(ConfigError::invalid("webhook.events", event,
+                                                    "expected now_playing or scrobble"));
+                }
+            }
+        }
+
+        let secret = match section.secret {
+            Some(secret) => Some(try!(secrets::resolve(&secret)
+                .map_err(|err| ConfigError::Secret("webhook.secret", err)))),
+            None => None,
+        };
+
+        hooks.push(WebhookConfig {
+            url: section.url,
+            events: events,
+            secret: secret,
+            template: section.template,
+        });
+    }
+    Ok(hooks)
+}
+
+/// The Connect device name of an account, and the label it is logged with.
+fn account_name(account: &AccountSection, id: Option<&str>) -> (String, String) {
+    let name = account.name.clone().unwrap_or_else(|| match id {
+        Some(id) => format!("Scrobbler ({})", id),
+        None => String::from("Scrobbler"),
     });
+    let label = id.map(str::to_owned).unwrap_or_else(|| name.clone());
 
-    let cached_credentials = cache.as_ref().and_then(Cache::credentials);
-    let credentials = get_credentials(matches.opt_str("spotify-username"),
-                                      matches.opt_str("spotify-password"),
-                                      cached_credentials);
+    (name, label)
+}
 
-    let session_config = {
-        let device_id = librespot::core::session::device_id(&name);
+fn resolve_scrobbler(account: &AccountSection, id: Option<&str>, cache: Option<&Cache>,
+                     history: Option<HistoryConfig>, stats: bool, webhooks: Webhooks)
+    -> Result<ScrobblerConfig, ConfigError>
+{
+    let api_key = try!(require(account.lastfm_api_key.clone(), id, &LASTFM_API_KEY));
+    let api_secret = try!(require(account.lastfm_api_secret.clone(), id, &LASTFM_API_SECRET));
+    let api_secret = try!(secret(api_secret, id, &LASTFM_API_SECRET));
+    let username = try!(require(account.lastfm_username.clone(), id, &LASTFM_USERNAME));
+    let password = try!(require(account.lastfm_password.clone(), id, &LASTFM_PASSWORD));
+    let password = try!(secret(password, id, &LASTFM_PASSWORD));
 
-        SessionConfig {
-            user_agent: version::version_string(),
-            device_id: device_id,
+    let excluded_content = match account.exclude_content {
+        Some(ref content) => {
+            let mut excluded_content = Vec::new();
+            for content in content {
+                // Ads are always excluded, and excluding music would scrobble nothing
+                match ContentType::from_str(content) {
+                    Ok(content_type @ ContentType::Episode) |
+                    Ok(content_type @ ContentType::Audiobook) => excluded_content.push(content_type),
+                    _ => {
+                        return Err(ConfigError::invalid("scrobbler.exclude_content", content.clone(),
+                                                        "expected episode or audiobook"));
+                    }
+                }
+            }
+            excluded_content
         }
+        None => DEFAULT_EXCLUDED_CONTENT.to_vec(),
     };
 
-    let api_key = matches.opt_str("lastfm-api-key").expect("Invalid Last.fm API key");
-    let api_secret = matches.opt_str("lastfm-api-secret").expect("Invalid Last.fm API secret");
-    let username = matches.opt_str("lastfm-username").expect("Invalid Last.fm username");
-    let password = matches.opt_str("lastfm-password").expect("Invalid Last.fm password");
-
-    let scrobbler_config = ScrobblerConfig {
+    Ok(ScrobblerConfig {
         api_key: api_key,
         api_secret: api_secret,
         username: username,
         password: password,
+        excluded_content: excluded_content,
+        included_devices: account.include_devices.clone().unwrap_or_default(),
+        excluded_devices: account.exclude_devices.clone().unwrap_or_default(),
+        scrobbled: ScrobbledPlays::default(),
+        history: match (history, cache) {
+            (Some(history), Some(cache)) => Some(HistoryLog::new(cache.location(), history)),
+            _ => None,
+        },
+        stores: match (stats, cache) {
+            (true, Some(cache)) => stats_stores(cache),
+            _ => Vec::new(),
+        },
+        status: ScrobblerStatus::new(),
+        webhooks: webhooks,
+    })
+}
+
+/// Resolves only what the offline commands need of an account: no Spotify
+/// settings, and the Last.fm ones only when a backfill sends plays there.
+fn resolve_offline_account(account: AccountSection, id: Option<&str>, cache: Option<Cache>,
+                           history: Option<HistoryConfig>, lastfm: bool)
+    -> Result<OfflineAccount, ConfigError>
+{
+    let (_, label) = account_name(&account, id);
+
+    let lastfm = if lastfm {
+        Some(try!(resolve_scrobbler(&account, id, cache.as_ref(), history, false, Webhooks::none())))
+    } else {
+        None
     };
 
+    Ok(OfflineAccount {
+        label: label,
+        history: match (history, cache.as_ref()) {
+            (Some(history), Some(cache)) => Some(HistoryLog::new(cache.location(), history)),
+            _ => None,
+        },
+        cache: cache,
+        lastfm: lastfm,
+    })
+}
+
+fn resolve_account(account: AccountSection, id: Option<&str>, cache: Option<Cache>,
+                   proxy: Option<Uri>, history: Option<HistoryConfig>, stats: bool,
+                   webhooks: &Webhooks)
+    -> Result<AccountSetup, ConfigError>
+{
+    let (name, label) = account_name(&account, id);
+
+    let enable_discovery = account.discovery.unwrap_or(false);
+
+    let cached_credentials = cached_credentials(cache.as_ref());
+    let credentials = {
+        let key = if id.is_some() { "spotify_password" } else { "session.password" };
+        try!(get_credentials(account.spotify_username.clone(), account.spotify_password.clone(),
+                             cached_credentials)
+            .map_err(|err| ConfigError::Secret(key, err)))
+    };
+
+    if credentials.is_none() && !enable_discovery {
+        // Without discovery there is no other way to sign in
+        try!(require(None, id, &SPOTIFY_USERNAME));
+    }
+
+    let session_config = {
+        let device_id = librespot::core::session::device_id(&name);
+
+        SessionConfig {
+            user_agent: version::version_string(),
+            device_id: device_id,
+            proxy: proxy,
+        }
+    };
+
+    let scrobbler_config = try!(resolve_scrobbler(&account, id, cache.as_ref(), history, stats,
+                                                  webhooks.for_account(&label)));
+
     let connect_config = {
-        let device_type = matches.opt_str("device-type").as_ref()
-            .map(|device_type| DeviceType::from_str(device_type).expect("Invalid device type"))
-            .unwrap_or(DeviceType::default());
+        let device_type = match account.device_type {
+            Some(device_type) => match DeviceType::from_str(&device_type) {
+                Ok(device_type) => device_type,
+                Err(()) => {
+                    return Err(ConfigError::invalid("connect.device_type", device_type,
+                                                    "unknown device type"));
+                }
+            },
+            None => DeviceType::default(),
+        };
+
+        let command_policy = match account.playback_commands {
+            Some(policy) => match CommandPolicy::from_str(&policy) {
+                Ok(policy) => policy,
+                Err(()) => {
+                    return Err(ConfigError::invalid("connect.playback_commands", policy,
+                                                    "expected reject or hand-off"));
+                }
+            },
+            None => CommandPolicy::default(),
+        };
+
+        let hidden = account.hidden.unwrap_or(false);
 
         ConnectConfig {
             name: name,
             device_type: device_type,
+            can_be_player: !hidden,
+            hidden: hidden,
+            supports_rename: account.rename.unwrap_or(false),
+            command_policy: command_policy,
         }
     };
 
-    Setup {
+    Ok(AccountSetup {
+        label: label,
         cache: cache,
         session_config: session_config,
         connect_config: connect_config,
         credentials: credentials,
+        enable_discovery: enable_discovery,
         scrobbler_config: scrobbler_config
-    }
+    })
 }
 
-struct Main {
+// Delay before reconnecting an account whose session failed, doubled on
+// every failure in a row.
+const RESTART_DELAY_MIN: u64 = 5;
+const RESTART_DELAY_MAX: u64 = 300;
+
+const RECONNECTS: Metric = Metric {
+    name: "scrobbler_session_reconnects_total",
+    help: "Reconnects to Spotify after a session failed.",
+    kind: Kind::Counter,
+};
+
+/// One Spotify account with its own session, Connect device and scrobbler.
+/// A failing account is restarted on its own, without affecting the others.
+struct Account {
+    label: String,
     cache: Option<Cache>,
     session_config: SessionConfig,
     connect_config: ConnectConfig,
     handle: Handle,
 
-    signal: IoStream<()>,
+    credentials: Option<Credentials>,
+    discovery: Option<DiscoveryStream>,
 
     spirc: Option<Spirc>,
     spirc_task: Option<SpircTask>,
+    /// Checks the subscription while connected.
+    probe: Option<Interval>,
     connect: Box<Future<Item=Session, Error=io::Error>>,
 
+    restart: Option<Timeout>,
+    restart_delay: Duration,
+
     scrobbler_config: ScrobblerConfig,
 
     shutdown: bool,
 }
 
-impl Main {
-    fn new(handle: Handle, setup: Setup) -> Main {
-        let mut task = Main {
+impl Account {
+    fn new(handle: Handle, setup: AccountSetup) -> Account {
+        let mut account = Account {
+            label: setup.label,
             handle: handle.clone(),
             cache: setup.cache,
             session_config: setup.session_config,
             connect_config: setup.connect_config,
 
+            credentials: None,
+            discovery: None,
+
             connect: Box::new(futures::future::empty()),
             spirc: None,
             spirc_task: None,
+            probe: None,
+
+            restart: None,
+            restart_delay: Duration::from_secs(RESTART_DELAY_MIN),
+
+            scrobbler_config: setup.scrobbler_config,
+
             shutdown: false,
-            signal: tokio_signal::ctrl_c(&handle).flatten_stream().boxed(),
-            scrobbler_config: setup.scrobbler_config
         };
 
+        if setup.enable_discovery {
+            let config = account.connect_config.clone();
+            let device_id = account.session_config.device_id.clone();
+
+            match discovery(&handle, config, device_id) {
+                Ok(discovery) => account.discovery = Some(discovery),
+                Err(err) => error!("[{}] Could not start discovery: {}", account.label, err),
+            }
+        }
+
         if let Some(credentials) = setup.credentials {
-            task.credentials(credentials);
+            account.credentials(credentials);
         }
 
-        task
+        account
     }
 
     fn credentials(&mut self, credentials: Credentials) {
         let config = self.session_config.clone();
         let handle = self.handle.clone();
 
+        self.credentials = Some(credentials.clone());
         let connection = Session::connect(config, credentials, self.cache.clone(), handle);
 
         self.connect = connection;
-        self.spirc = None;
-        let task = mem::replace(&mut self.spirc_task, None);
-        if let Some(task) = task {
+        self.restart = None;
+        if let Some(spirc) = self.spirc.take() {
+            spirc.shutdown();
+        }
+        if let Some(task) = self.spirc_task.take() {
             self.handle.spawn(task);
         }
     }
+
+    fn restart_later(&mut self) {
+        let delay = self.restart_delay;
+        self.restart_delay = cmp::min(delay * 2, Duration::from_secs(RESTART_DELAY_MAX));
+
+        warn!("[{}] Reconnecting in {} seconds", self.label, delay.as_secs());
+        match Timeout::new(delay, &self.handle) {
+            Ok(timeout) => self.restart = Some(timeout),
+            Err(err) => error!("[{}] Could not schedule reconnect: {}", self.label, err),
+        }
+    }
+
+    fn reconnect(&mut self) {
+        RECONNECTS.inc(&[]);
+
+        // Prefer the reusable credentials saved by the last successful login
+        let credentials = cached_credentials(self.cache.as_ref())
+            .or_else(|| self.credentials.clone());
+
+        if let Some(credentials) = credentials {
+            self.credentials(credentials);
+        }
+    }
+
+    fn shutdown(&mut self) {
+        self.shutdown = true;
+        self.discovery = None;
+        self.restart = None;
+        self.connect = Box::new(futures::future::empty());
+
+        if let Some(ref spirc) = self.spirc {
+            spirc.shutdown();
+        }
+    }
 }
 
-impl Future for Main {
+impl Future for Account {
     type Item = ();
     type Error = ();
 
@@ -210,21 +1001,141 @@ impl Future for Main {
         loop {
             let mut progress = false;
 
-            if let Async::Ready(session) = self.connect.poll().unwrap() {
-                self.connect = Box::new(futures::future::empty());
-                let connect_config = self.connect_config.clone();
+            if let Some(Async::Ready(Some(credentials))) = self.discovery.as_mut().map(|d| d.poll().unwrap()) {
+                self.credentials(credentials);
+
+                progress = true;
+            }
+
+            match self.connect.poll() {
+                Ok(Async::Ready(session)) => {
+                    self.connect = Box::new(futures::future::empty());
+                    let connect_config = self.connect_config.clone();
+
+                    let scrobbler = Scrobbler::new(self.scrobbler_config.clone(), session.clone());
 
-                let (spirc, spirc_task) = Spirc::new(connect_config, session, self.scrobbler_config.clone());
-                self.spirc = Some(spirc);
-                self.spirc_task = Some(spirc_task);
+                    let (spirc, spirc_task) = Spirc::new(connect_config, session, scrobbler);
+                    self.scrobbler_config.status.connected(&spirc);
+                    self.probe = match Interval::new(Duration::from_secs(PROBE_INTERVAL_SECS), &self.handle) {
+                        Ok(probe) => Some(probe),
+                        Err(err) => {
+                            warn!("[{}] Cannot schedule subscription checks: {}", self.label, err);
+                            None
+                        }
+                    };
+                    self.spirc = Some(spirc);
+                    self.spirc_task = Some(spirc_task);
+                    self.restart_delay = Duration::from_secs(RESTART_DELAY_MIN);
+
+                    progress = true;
+                }
+                Ok(Async::NotReady) => (),
+                Err(err) => {
+                    self.connect = Box::new(futures::future::empty());
+                    error!("[{}] Could not connect to Spotify: {}", self.label, err);
+                    self.restart_later();
+
+                    progress = true;
+                }
+            }
+
+            let probe_due = match self.probe.as_mut().map(|probe| probe.poll()) {
+                Some(Ok(Async::Ready(Some(())))) => true,
+                Some(Ok(_)) | None => false,
+                Some(Err(err)) => {
+                    warn!("[{}] Cannot schedule subscription checks: {}", self.label, err);
+                    self.probe = None;
+                    false
+                }
+            };
+
+            if probe_due {
+                if let Some(ref spirc) = self.spirc {
+                    spirc.probe();
+                }
+
+                progress = true;
+            }
+
+            if let Some(Async::Ready(())) = self.restart.as_mut().map(|timeout| timeout.poll().unwrap()) {
+                self.restart = None;
+                self.reconnect();
+
+                progress = true;
+            }
+
+            let finished = match self.spirc_task.as_mut().map(|task| task.poll()) {
+                Some(Ok(Async::NotReady)) | None => false,
+                Some(_) => true,
+            };
+
+            if finished {
+                self.spirc = None;
+                self.spirc_task = None;
+                self.probe = None;
+                self.scrobbler_config.status.disconnected();
+
+                if !self.shutdown {
+                    error!("[{}] Spirc shut down unexpectedly", self.label);
+                    self.restart_later();
+                }
 
                 progress = true;
             }
 
+            if self.shutdown && self.spirc_task.is_none() {
+                return Ok(Async::Ready(()));
+            }
+
+            if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+struct Main {
+    signal: IoStream<()>,
+    accounts: Vec<Account>,
+
+    shutdown: bool,
+}
+
+impl Main {
+    fn new(handle: Handle, setup: Setup) -> Main {
+        if let Some(config) = setup.http {
+            let statuses = setup.accounts.iter()
+                .map(|account| (account.label.clone(), account.scrobbler_config.status.clone()))
+                .collect();
+
+            match http_api::serve(&handle, config, statuses) {
+                Ok(addr) => info!("Status API listening on {}", addr),
+                Err(err) => error!("Could not start the status API: {}", err),
+            }
+        }
+
+        Main {
+            accounts: setup.accounts.into_iter()
+                .map(|account| Account::new(handle.clone(), account))
+                .collect(),
+            shutdown: false,
+            signal: tokio_signal::ctrl_c(&handle).flatten_stream().boxed(),
+        }
+    }
+}
+
+impl Future for Main {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            let mut progress = false;
+
             if let Async::Ready(Some(())) = self.signal.poll().unwrap() {
                 if !self.shutdown {
-                    if let Some(ref spirc) = self.spirc {
-                        spirc.shutdown();
+                    for account in &mut self.accounts {
+                        account.shutdown();
                     }
                     self.shutdown = true;
                 } else {
@@ -234,16 +1145,20 @@ impl Future for Main {
                 progress = true;
             }
 
-            if let Some(ref mut spirc_task) = self.spirc_task {
-                if let Async::Ready(()) = spirc_task.poll().unwrap() {
-                    if self.shutdown {
-                        return Ok(Async::Ready(()));
-                    } else {
-                        panic!("Spirc shut down unexpectedly");
-                    }
+            // Accounts only finish once they have been shut down
+            let mut i = 0;
+            while i < self.accounts.len() {
+                if let Ok(Async::Ready(())) = self.accounts[i].poll() {
+                    self.accounts.remove(i);
+                } else {
+                    i += 1;
                 }
             }
 
+            if self.shutdown && self.accounts.is_empty() {
+                return Ok(Async::Ready(()));
+            }
+
             if !progress {
                 return Ok(Async::NotReady);
             }
@@ -251,11 +1166,139 @@ impl Future for Main {
     }
 }
 
+fn run_backfill(accounts: Vec<OfflineAccount>, backfill: BackfillSetup) -> ! {
+    let mut failed = false;
+
+    for account in accounts {
+        match backfill_account(&account, &backfill) {
+            Ok(report) => {
+                println!("[{}] {} {} plays, skipped {} sent before and {} too old to send",
+                         account.label, if backfill.options.dry_run { "Would send" } else { "Sent" },
+                         report.submitted, report.already_acknowledged, report.too_old);
+            }
+            Err(err) => {
+                writeln!(stderr(), "[{}] error: {}", account.label, err).unwrap();
+                failed = true;
+            }
+        }
+    }
+
+    exit(if failed { 1 } else { 0 })
+}
+
+fn backfill_account(account: &OfflineAccount, backfill: &BackfillSetup) -> Result<BackfillReport, String> {
+    let (history, cache) = match (account.history.as_ref(), account.cache.as_ref()) {
+        (Some(history), Some(cache)) => (history, cache),
+        _ => return Err("no play history is kept, see --history".to_owned()),
+    };
+
+    let entries = try!(history.read()
+        .map_err(|err| format!("cannot read {}: {}", history.path().display(), err)));
+
+    let mut destination: Box<Destination> = match backfill.destination {
+        BackfillDestination::LastFm => match account.lastfm {
+            Some(ref config) => Box::new(try!(LastFm::connect(config, Some(cache)))),
+            None => return Err("no Last.fm settings".to_owned()),
+        },
+        BackfillDestination::ListenBrainz { ref token } => Box::new(ListenBrainz::new(token.clone())),
+    };
+
+    let mut ledger = try!(AckLedger::open(cache.location(), &*destination)
+        .map_err(|err| format!("cannot read acknowledged plays: {}", err)));
+
+    backfill::backfill(entries, &mut *destination, &mut ledger, &backfill.options)
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(feature = "stats")]
+fn stats_stores(cache: &Cache) -> Vec<Arc<PlayStore>> {
+    match StatsStore::open(cache.location()) {
+        Ok(store) => vec![Arc::new(store) as Arc<PlayStore>],
+        Err(err) => {
+            warn!("Cannot open the statistics database in {}: {}", cache.location().display(), err);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+fn stats_stores(_cache: &Cache) -> Vec<Arc<PlayStore>> {
+    warn!("Listening statistics need a build with the stats feature");
+    Vec::new()
+}
+
+#[cfg(feature = "stats")]
+fn run_stats(accounts: Vec<OfflineAccount>, stats: StatsSetup) -> ! {
+    let mut failed = false;
+
+    for account in accounts {
+        if let Err(err) = print_stats(&account, &stats) {
+            writeln!(stderr(), "[{}] error: {}", account.label, err).unwrap();
+            failed = true;
+        }
+    }
+
+    exit(if failed { 1 } else { 0 })
+}
+
+#[cfg(not(feature = "stats"))]
+fn run_stats(_accounts: Vec<OfflineAccount>, _stats: StatsSetup) -> ! {
+    writeln!(stderr(), "error: this build has no listening statistics, rebuild with --features stats").unwrap();
+    exit(1)
+}
+
+#[cfg(feature = "stats")]
+fn print_stats(account: &OfflineAccount, stats: &StatsSetup) -> Result<(), String> {
+    let cache = match account.cache.as_ref() {
+        Some(cache) => cache,
+        None => return Err("no statistics are kept without a cache directory".to_owned()),
+    };
+
+    let store = try!(StatsStore::open(cache.location()).map_err(|err| err.to_string()));
+    let from = stats.from.unwrap_or(0);
+    let until = stats.until.unwrap_or(i64::max_value());
+
+    println!("[{}]", account.label);
+    let sections = [
+        ("Top artists", store.top_artists(from, until, stats.top)),
+        ("Top albums", store.top_albums(from, until, stats.top)),
+        ("Top tracks", store.top_tracks(from, until, stats.top)),
+        ("Plays per device", store.plays_per_device(from, until, stats.top)),
+    ];
+
+    for &(title, ref ranked) in sections.iter() {
+        let ranked = try!(ranked.as_ref()
+            .map_err(|err| format!("cannot read {}: {}", store.path().display(), err)));
+        print_ranking(title, ranked);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "stats")]
+fn print_ranking(title: &str, ranked: &[Ranked]) {
+    println!("\n{}", title);
+    if ranked.is_empty() {
+        println!("    (nothing played)");
+    }
+    for (index, entry) in ranked.iter().enumerate() {
+        println!("{:4}. {} ({} plays, {} min)", index + 1, entry.name, entry.plays,
+                 entry.played_ms / 60_000);
+    }
+}
+
 fn main() {
     let mut core = Core::new().unwrap();
     let handle = core.handle();
 
     let args: Vec<String> = std::env::args().collect();
+    let mut setup = setup(&args);
+
+    match std::mem::replace(&mut setup.command, Command::Run) {
+        Command::Run => (),
+        Command::Backfill(backfill) => run_backfill(setup.offline_accounts, backfill),
+        Command::Stats(stats) => run_stats(setup.offline_accounts, stats),
+    }
 
-    core.run(Main::new(handle, setup(&args))).unwrap()
+    core.run(Main::new(handle, setup)).unwrap()
 }
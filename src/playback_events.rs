@@ -0,0 +1,383 @@
+use futures::{Async, Poll};
+use std::time::{Duration, Instant};
+
+use core::util::SpotifyUri;
+use protocol::spirc::PlayStatus;
+use spirc_state::{ContentType, PlaybackDevice, PlaybackState};
+
+// Positions sent by devices drift a little between notifications, jumps
+// further than this are taken as a seek.
+const SEEK_TOLERANCE_MS: i64 = 3000;
+
+/// What happened on the account's active device, derived from the
+/// successive states it sends.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlaybackEvent {
+    /// A track started playing, or was already playing when we first saw it.
+    TrackStarted(PlaybackItem),
+    TrackPaused { item: PlaybackItem, position_ms: u32 },
+    Resumed { item: PlaybackItem, position_ms: u32 },
+    Seeked { item: PlaybackItem, position_ms: u32 },
+    /// The track was replaced by another one, or playback stopped.
+    TrackEnded { item: PlaybackItem, played_ms: u64 },
+    /// Playback moved to another device, or we saw the first active device.
+    DeviceChanged { from: Option<PlaybackDevice>, to: PlaybackDevice },
+    ContextChanged { device: PlaybackDevice, context_uri: Option<String> },
+}
+
+/// A single play of a track.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaybackItem {
+    pub track: SpotifyUri,
+    pub content: ContentType,
+    /// The device playing it, the last one if playback moved mid-track.
+    pub device: PlaybackDevice,
+    pub context_uri: Option<String>,
+    /// When the track started according to the device playing it,
+    /// `position_measured_at - position_ms`.
+    pub started_at: i64,
+}
+
+/// Something that acts upon playback, such as the Last.fm scrobbler, driven
+/// by the `SpircTask` it is given to.
+///
+/// The scrobbler is an observer rather than a consumer of `Spirc::events`:
+/// its requests are then polled on the account's own task, and it sees each
+/// event before anything subscribed, so the status it keeps is up to date
+/// by the time subscribers hear of the event.
+pub trait PlaybackObserver {
+    fn handle_event(&mut self, event: &PlaybackEvent);
+
+    /// Makes progress on work started by earlier events, polled along with
+    /// the `SpircTask`. An error is logged and the observer dropped, without
+    /// stopping the task.
+    fn poll(&mut self) -> Poll<(), ()> {
+        Ok(Async::NotReady)
+    }
+}
+
+/// Observes nothing, for when `Spirc::events` is all that's needed.
+impl PlaybackObserver for () {
+    fn handle_event(&mut self, _event: &PlaybackEvent) {}
+}
+
+/// Turns the states sent by the active device into `PlaybackEvent`s.
+pub struct EventTracker {
+    device: Option<PlaybackDevice>,
+    context_uri: Option<String>,
+    current: Option<CurrentPlay>,
+}
+
+struct CurrentPlay {
+    item: PlaybackItem,
+    status: PlayStatus,
+    position_ms: u32,
+    position_measured_at: u64,
+    played: Duration,
+    playing_since: Option<Instant>,
+}
+
+impl CurrentPlay {
+    fn played_ms(&self) -> u64 {
+        let played = match self.playing_since {
+            Some(since) => self.played + since.elapsed(),
+            None => self.played,
+        };
+        played.as_secs() * 1000 + played.subsec_nanos() as u64 / 1_000_000
+    }
+
+    /// Where playback should be at `measured_at` had nobody touched it.
+    fn expected_position_ms(&self, measured_at: u64) -> i64 {
+        if self.status == PlayStatus::kPlayStatusPlay {
+            self.position_ms as i64 + (measured_at as i64 - self.position_measured_at as i64)
+        } else {
+            self.position_ms as i64
+        }
+    }
+}
+
+impl EventTracker {
+    pub fn new() -> EventTracker {
+        EventTracker {
+            device: None,
+            context_uri: None,
+            current: None,
+        }
+    }
+
+    /// Whether `ident` is the device whose playback we're following.
+    pub fn is_following(&self, ident: &str) -> bool {
+        self.device.as_ref().map_or(false, |device| device.ident == ident)
+    }
+
+    /// The events leading up to `state`, as sent by the active `device`.
+    pub fn update(&mut self, device: &PlaybackDevice, state: &PlaybackState) -> Vec<PlaybackEvent> {
+        let mut events = Vec::new();
+
+        if !self.is_following(&device.ident) {
+            events.push(PlaybackEvent::DeviceChanged {
+                from: self.device.take(),
+                to: device.clone(),
+            });
+        }
+        self.device = Some(device.clone());
+
+        if self.context_uri != state.context_uri {
+            self.context_uri = state.context_uri.clone();
+            events.push(PlaybackEvent::ContextChanged {
+                device: device.clone(),
+                context_uri: state.context_uri.clone(),
+            });
+        }
+
+        let track = match state.track {
+            Some(ref track) if state.status != PlayStatus::kPlayStatusStop => track.clone(),
+            _ => {
+                self.end(&mut events);
+                return events;
+            }
+        };
+
+        let is_new_track = match self.current {
+            None => true,
+            // Going back to the start of the same track plays it again
+            Some(ref current) => {
+                current.item.track != track ||
+                (state.position_ms == 0 &&
+                 current.expected_position_ms(state.position_measured_at) > SEEK_TOLERANCE_MS)
+            }
+        };
+
+        if is_new_track {
+            self.end(&mut events);
+
+            let item = PlaybackItem {
+                track: track,
+                content: state.content,
+                device: device.clone(),
+                context_uri: state.context_uri.clone(),
+                started_at: state.position_measured_at as i64 - state.position_ms as i64,
+            };
+            let playing_since = if state.status == PlayStatus::kPlayStatusPlay {
+                Some(Instant::now())
+            } else {
+                None
+            };
+
+            events.push(PlaybackEvent::TrackStarted(item.clone()));
+            self.current = Some(CurrentPlay {
+                item: item,
+                status: state.status,
+                position_ms: state.position_ms,
+                position_measured_at: state.position_measured_at,
+                played: Duration::from_secs(0),
+                playing_since: playing_since,
+            });
+            return events;
+        }
+
+        let current = self.current.as_mut().unwrap();
+        current.item.device = device.clone();
+        current.item.context_uri = state.context_uri.clone();
+
+        let was_playing = current.status == PlayStatus::kPlayStatusPlay;
+        let is_playing = state.status == PlayStatus::kPlayStatusPlay;
+
+        if was_playing && !is_playing {
+            if let Some(since) = current.playing_since.take() {
+                current.played += since.elapsed();
+            }
+            events.push(PlaybackEvent::TrackPaused {
+                item: current.item.clone(),
+                position_ms: state.position_ms,
+            });
+        } else if !was_playing && is_playing {
+            current.playing_since = Some(Instant::now());
+            events.push(PlaybackEvent::Resumed {
+                item: current.item.clone(),
+                position_ms: state.position_ms,
+            });
+        }
+
+        let expected_position_ms = current.expected_position_ms(state.position_measured_at);
+        if (state.position_ms as i64 - expected_position_ms).abs() > SEEK_TOLERANCE_MS {
+            events.push(PlaybackEvent::Seeked {
+                item: current.item.clone(),
+                position_ms: state.position_ms,
+            });
+        }
+
+        current.status = state.status;
+        current.position_ms = state.position_ms;
+        current.position_measured_at = state.position_measured_at;
+
+        events
+    }
+
+    /// The device we were following stopped playing or left.
+    pub fn stop(&mut self) -> Vec<PlaybackEvent> {
+        let mut events = Vec::new();
+        self.end(&mut events);
+        events
+    }
+
+    fn end(&mut self, events: &mut Vec<PlaybackEvent>) {
+        if let Some(current) = self.current.take() {
+            events.push(PlaybackEvent::TrackEnded {
+                played_ms: current.played_ms(),
+                item: current.item,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::config::DeviceType;
+    use core::util::SpotifyId;
+
+    const PLAY: PlayStatus = PlayStatus::kPlayStatusPlay;
+    const PAUSE: PlayStatus = PlayStatus::kPlayStatusPause;
+    const STOP: PlayStatus = PlayStatus::kPlayStatusStop;
+
+    /// A state sent by a device: its ident, the track, the status, the
+    /// position and when it was measured.
+    type Step = (&'static str, u8, PlayStatus, u32, u64);
+
+    fn device(ident: &str) -> PlaybackDevice {
+        PlaybackDevice {
+            ident: ident.to_owned(),
+            name: ident.to_uppercase(),
+            device_type: DeviceType::Speaker,
+        }
+    }
+
+    fn state(track: u8, status: PlayStatus, position_ms: u32, position_measured_at: u64) -> PlaybackState {
+        PlaybackState {
+            status: status,
+            context_uri: None,
+            position_ms: position_ms,
+            position_measured_at: position_measured_at,
+            playing_from_fallback: false,
+            track: Some(SpotifyUri::Track(SpotifyId::from_raw(&[track; 16]))),
+            ad: None,
+            content: ContentType::Music,
+        }
+    }
+
+    // How long was played depends on the clock, so it is left out
+    fn describe(event: &PlaybackEvent) -> String {
+        let track = |item: &PlaybackItem| item.track.id().unwrap().to_raw()[0];
+
+        match *event {
+            PlaybackEvent::TrackStarted(ref item) => format!("started {}", track(item)),
+            PlaybackEvent::TrackPaused { ref item, position_ms } => {
+                format!("paused {} at {}", track(item), position_ms)
+            }
+            PlaybackEvent::Resumed { ref item, position_ms } => {
+                format!("resumed {} at {}", track(item), position_ms)
+            }
+            PlaybackEvent::Seeked { ref item, position_ms } => {
+                format!("seeked {} to {}", track(item), position_ms)
+            }
+            PlaybackEvent::TrackEnded { ref item, .. } => format!("ended {}", track(item)),
+            PlaybackEvent::DeviceChanged { ref from, ref to } => {
+                let from = from.as_ref().map_or("-", |from| &from.ident[..]);
+                format!("device {} -> {}", from, to.ident)
+            }
+            PlaybackEvent::ContextChanged { ref context_uri, .. } => format!("context {:?}", context_uri),
+        }
+    }
+
+    #[test]
+    fn tracks_playback() {
+        let cases: Vec<(&str, Vec<(Step, Vec<&str>)>)> = vec![
+            ("play", vec![
+                (("a", 1, PLAY, 0, 1000), vec!["device - -> a", "started 1"]),
+                (("a", 1, PLAY, 5000, 6000), vec![]),
+            ]),
+            ("starts paused", vec![
+                (("a", 1, PAUSE, 30000, 1000), vec!["device - -> a", "started 1"]),
+                (("a", 1, PAUSE, 30000, 9000), vec![]),
+            ]),
+            ("pause and resume", vec![
+                (("a", 1, PLAY, 0, 1000), vec!["device - -> a", "started 1"]),
+                (("a", 1, PAUSE, 5000, 6000), vec!["paused 1 at 5000"]),
+                (("a", 1, PLAY, 5000, 60000), vec!["resumed 1 at 5000"]),
+            ]),
+            ("drift within tolerance", vec![
+                (("a", 1, PLAY, 0, 1000), vec!["device - -> a", "started 1"]),
+                (("a", 1, PLAY, 7000, 6000), vec![]),
+                (("a", 1, PLAY, 12000, 12000), vec![]),
+            ]),
+            ("seek beyond tolerance", vec![
+                (("a", 1, PLAY, 0, 1000), vec!["device - -> a", "started 1"]),
+                (("a", 1, PLAY, 60000, 6000), vec!["seeked 1 to 60000"]),
+                (("a", 1, PLAY, 10000, 7000), vec!["seeked 1 to 10000"]),
+            ]),
+            ("seek while paused", vec![
+                (("a", 1, PAUSE, 5000, 1000), vec!["device - -> a", "started 1"]),
+                (("a", 1, PAUSE, 90000, 60000), vec!["seeked 1 to 90000"]),
+            ]),
+            ("track change", vec![
+                (("a", 1, PLAY, 0, 1000), vec!["device - -> a", "started 1"]),
+                (("a", 2, PLAY, 0, 200000), vec!["ended 1", "started 2"]),
+            ]),
+            ("replay from the start", vec![
+                (("a", 1, PLAY, 0, 1000), vec!["device - -> a", "started 1"]),
+                (("a", 1, PLAY, 0, 200000), vec!["ended 1", "started 1"]),
+            ]),
+            ("stop", vec![
+                (("a", 1, PLAY, 0, 1000), vec!["device - -> a", "started 1"]),
+                (("a", 1, STOP, 0, 6000), vec!["ended 1"]),
+            ]),
+            ("device change mid-track", vec![
+                (("a", 1, PLAY, 0, 1000), vec!["device - -> a", "started 1"]),
+                (("b", 1, PLAY, 5000, 6000), vec!["device a -> b"]),
+                (("b", 2, PLAY, 0, 200000), vec!["ended 1", "started 2"]),
+            ]),
+            ("device change to another track", vec![
+                (("a", 1, PLAY, 0, 1000), vec!["device - -> a", "started 1"]),
+                (("b", 2, PAUSE, 0, 6000), vec!["device a -> b", "ended 1", "started 2"]),
+            ]),
+        ];
+
+        for (name, steps) in cases {
+            let mut tracker = EventTracker::new();
+
+            for (i, ((ident, track, status, position_ms, measured_at), expected)) in steps.into_iter().enumerate() {
+                let events = tracker.update(&device(ident), &state(track, status, position_ms, measured_at));
+                let events: Vec<String> = events.iter().map(describe).collect();
+                assert_eq!(events, expected, "{}, step {}", name, i);
+                assert!(tracker.is_following(ident), "{}, step {}", name, i);
+            }
+        }
+    }
+
+    #[test]
+    fn stop_ends_current_track() {
+        let mut tracker = EventTracker::new();
+        assert!(tracker.stop().is_empty());
+
+        tracker.update(&device("a"), &state(1, PLAY, 0, 1000));
+        let events: Vec<String> = tracker.stop().iter().map(describe).collect();
+        assert_eq!(events, vec!["ended 1"]);
+        assert!(tracker.stop().is_empty());
+    }
+
+    #[test]
+    fn ended_track_keeps_last_device() {
+        let mut tracker = EventTracker::new();
+        tracker.update(&device("a"), &state(1, PLAY, 0, 1000));
+        tracker.update(&device("b"), &state(1, PLAY, 5000, 6000));
+
+        match tracker.stop().pop() {
+            Some(PlaybackEvent::TrackEnded { item, .. }) => {
+                assert_eq!(item.device, device("b"));
+                assert_eq!(item.started_at, 1000);
+            }
+            other => panic!("expected TrackEnded, got {:?}", other),
+        }
+    }
+}
@@ -1,12 +1,22 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use futures::{Future, BoxFuture, Async, Poll};
+use futures::{Future, BoxFuture, Async, IntoFuture, Poll, Stream};
 use futures::future;
+use futures::sync::mpsc;
 use rustfm_scrobble::{self, Scrobble};
 
+use history::{HistoryEntry, HistoryLog, PlayStore, ScrobbleOutcome};
 use metadata::{Track, Artist, Album, Metadata};
+use playback_events::{PlaybackEvent, PlaybackItem, PlaybackObserver};
+use core::cache::Cache;
+use core::metrics::{Kind, Metric};
 use core::session::Session;
-use core::util::SpotifyId;
+use core::util::{now_ms, SpotifyId, SpotifyUri};
+use spirc_state::{ContentType, PlaybackDevice};
+use status::{AuthState, ScrobblerCommand, ScrobblerStatus};
+use webhooks::{Notification, WebhookEvent, Webhooks};
 
 #[derive(Clone, Debug)]
 pub struct ScrobblerConfig {
@@ -14,6 +24,104 @@ pub struct ScrobblerConfig {
     pub api_secret: String,
     pub username: String,
     pub password: String,
+    /// Content types which are never scrobbled, in addition to ads,
+    /// `DEFAULT_EXCLUDED_CONTENT` unless configured otherwise.
+    pub excluded_content: Vec<ContentType>,
+    /// Names or idents of the only devices to scrobble from, if non-empty.
+    pub included_devices: Vec<String>,
+    /// Names or idents of devices never to scrobble from.
+    pub excluded_devices: Vec<String>,
+    /// Plays scrobbled so far, shared by every scrobbler created from this
+    /// config so a reconnect in the middle of a track doesn't scrobble it twice.
+    pub scrobbled: ScrobbledPlays,
+    /// Where every play is recorded, whether scrobbled or not.
+    pub history: Option<HistoryLog>,
+    pub stores: Vec<Arc<PlayStore>>,
+    /// Shared with the HTTP API, which can pause scrobbling.
+    pub status: ScrobblerStatus,
+    /// Told when a track starts that will be scrobbled, and when it is.
+    pub webhooks: Webhooks,
+}
+
+/// Episodes have no track metadata to scrobble, and audiobooks would flood
+/// the Last.fm library with chapters.
+pub const DEFAULT_EXCLUDED_CONTENT: &'static [ContentType] = &[ContentType::Episode, ContentType::Audiobook];
+
+const SCROBBLES: Metric = Metric {
+    name: "scrobbler_scrobbles_total",
+    help: "Scrobbles sent, by sink and result.",
+    kind: Kind::Counter,
+};
+
+const NOW_PLAYING: Metric = Metric {
+    name: "scrobbler_now_playing_total",
+    help: "Now-playing updates sent to Last.fm, by result.",
+    kind: Kind::Counter,
+};
+
+const PENDING_SCROBBLES: Metric = Metric {
+    name: "scrobbler_pending_scrobbles",
+    help: "Scrobbles waiting for an answer, at most one per account.",
+    kind: Kind::Gauge,
+};
+
+const LAST_SCROBBLE: Metric = Metric {
+    name: "scrobbler_last_scrobble_timestamp_seconds",
+    help: "When the last scrobble went through, in seconds since the epoch.",
+    kind: Kind::Gauge,
+};
+
+// How far apart two start times of the same track may be and still be the
+// same play, as devices measure positions with some jitter.
+const PLAY_START_TOLERANCE_MS: i64 = 5000;
+const SCROBBLED_PLAYS_KEPT: usize = 64;
+
+/// A play of a track, identified by when it started according to the
+/// device playing it (`position_measured_at - position_ms`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Play {
+    pub track: SpotifyUri,
+    pub started_at: i64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScrobbledPlays(Arc<Mutex<VecDeque<Play>>>);
+
+impl ScrobbledPlays {
+    pub fn contains(&self, play: &Play) -> bool {
+        self.0.lock().unwrap().iter().any(|scrobbled| {
+            scrobbled.track == play.track &&
+            (scrobbled.started_at - play.started_at).abs() < PLAY_START_TOLERANCE_MS
+        })
+    }
+
+    pub fn insert(&self, play: Play) {
+        let mut plays = self.0.lock().unwrap();
+        if plays.len() >= SCROBBLED_PLAYS_KEPT {
+            plays.pop_front();
+        }
+        plays.push_back(play);
+    }
+}
+
+/// What we know about a track, for scrobbling it and for the history log.
+#[derive(Debug, Clone)]
+pub struct TrackInfo {
+    pub name: String,
+    /// The main artist, first of `artists`.
+    pub artist: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub cover_url: Option<String>,
+    pub artist_uri: Option<String>,
+    pub album_uri: Option<String>,
+    pub duration_ms: Option<u32>,
+}
+
+impl TrackInfo {
+    pub fn to_scrobble(&self) -> Scrobble {
+        Scrobble::new(&self.artist, &self.name, &self.album)
+    }
 }
 
 pub struct Scrobbler {
@@ -21,16 +129,25 @@ pub struct Scrobbler {
     scrobbler: rustfm_scrobble::Scrobbler,
 
     session: Box<Session>,
-    current_track_id: Option<SpotifyId>,
+    current_track_id: Option<SpotifyUri>,
+    current_track_started_at: i64,
+    current_track_device: Option<PlaybackDevice>,
     current_track_start: Option<Instant>,
-    current_track_meta: Option<Scrobble>,
+    current_track_meta: Option<TrackInfo>,
     current_track_scrobbled: bool,
+    current_track_scrobble_failed: bool,
+    current_track_excluded: bool,
 
     auth_future: BoxFuture<(), rustfm_scrobble::ScrobblerError>,
     new_track_future: BoxFuture<(), ()>,
     now_playing_future: BoxFuture<(), ScrobbleError>,
-    meta_fetch_future: BoxFuture<Scrobble, ScrobbleError>,
-    scrobble_future: Option<BoxFuture<(), ScrobbleError>>
+    meta_fetch_future: BoxFuture<TrackInfo, ScrobbleError>,
+    scrobble_future: Option<BoxFuture<(), ScrobbleError>>,
+
+    commands: mpsc::UnboundedReceiver<ScrobblerCommand>,
+    /// Signed in with a session key cached by an earlier run, which Last.fm
+    /// may have revoked since.
+    cached_session: bool,
 }
 
 #[derive(Debug)]
@@ -48,21 +165,104 @@ impl ScrobbleError {
 
 }
 
+/// Signs in to Last.fm, with the session key cached by an earlier run if
+/// there is one, caching the new session key otherwise.
+pub fn authenticate(scrobbler: &mut rustfm_scrobble::Scrobbler, config: &ScrobblerConfig,
+                    cache: Option<&Cache>)
+    -> Result<(), rustfm_scrobble::ScrobblerError>
+{
+    if authenticate_with_cached_session(scrobbler, config, cache) {
+        Ok(())
+    } else {
+        authenticate_with_password(scrobbler, config, cache)
+    }
+}
+
+/// Uses the session key cached by an earlier run, if any, which saves
+/// sending the password again. Returns whether there was one.
+fn authenticate_with_cached_session(scrobbler: &mut rustfm_scrobble::Scrobbler, config: &ScrobblerConfig,
+                                    cache: Option<&Cache>)
+    -> bool
+{
+    match cache.map(|cache| cache.lastfm_session_key(&config.username)) {
+        Some(Ok(Some(session_key))) => {
+            scrobbler.authenticate_with_session_key(&session_key);
+            true
+        }
+        Some(Err(err)) => {
+            warn!("Ignoring cached Last.fm session: {}", err);
+            false
+        }
+        _ => false,
+    }
+}
+
+fn authenticate_with_password(scrobbler: &mut rustfm_scrobble::Scrobbler, config: &ScrobblerConfig,
+                              cache: Option<&Cache>)
+    -> Result<(), rustfm_scrobble::ScrobblerError>
+{
+    try!(scrobbler.authenticate_with_password(&config.username, &config.password));
+
+    if let (Some(cache), Some(session_key)) = (cache, scrobbler.session_key()) {
+        if let Err(err) = cache.save_lastfm_session_key(&config.username, session_key) {
+            warn!("Cannot cache Last.fm session: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// The filter stage: ads, and items other than tracks and local files
+/// which have no metadata to scrobble, are never scrobbled, other content
+/// types and devices only when not excluded in the config.
+///
+/// The metadata of excluded items is never fetched.
+fn is_excluded(config: &ScrobblerConfig, item: &PlaybackItem) -> bool {
+    let included_devices = &config.included_devices;
+    let excluded_devices = &config.excluded_devices;
+    let device = &item.device;
+    let scrobblable = match item.track {
+        SpotifyUri::Track(_) | SpotifyUri::Local { .. } => true,
+        _ => false,
+    };
+
+    !scrobblable ||
+    item.content == ContentType::Ad ||
+    config.excluded_content.contains(&item.content) ||
+    (!included_devices.is_empty() && !included_devices.iter().any(|rule| device.matches(rule))) ||
+    excluded_devices.iter().any(|rule| device.matches(rule))
+}
+
+/// Last.fm answers requests signed with an invalid session key (error 9)
+/// with a 403, which is all `rustfm_scrobble` lets through.
+fn is_invalid_session(err: &rustfm_scrobble::ScrobblerError) -> bool {
+    err.to_string().contains("(403")
+}
+
 impl Scrobbler {
 
     pub fn new(config: ScrobblerConfig, session: Session) -> Scrobbler {
+        let (commands_tx, commands_rx) = mpsc::unbounded();
+        config.status.attach(commands_tx);
+
         let mut scrobbler = Scrobbler {
             session: Box::new(session),
             scrobbler: rustfm_scrobble::Scrobbler::new(&config.api_key, &config.api_secret),
             current_track_id: None,
+            current_track_started_at: 0,
+            current_track_device: None,
             current_track_start: None,
             current_track_meta: None,
             current_track_scrobbled: false,
+            current_track_scrobble_failed: false,
+            current_track_excluded: false,
             auth_future: future::empty().boxed(),
             new_track_future: future::empty().boxed(),
             now_playing_future: future::empty().boxed(),
             meta_fetch_future: future::empty().boxed(),
             scrobble_future: None,
+            commands: commands_rx,
+            cached_session: false,
             config: config
         };
 
@@ -75,96 +275,301 @@ impl Scrobbler {
     }
 
     pub fn auth(&mut self) -> BoxFuture<(), rustfm_scrobble::ScrobblerError> {
-        match self.scrobbler.authenticate_with_password(&self.config.username, &self.config.password) {
-            Ok(_) => future::ok(()),
-            Err(err) => future::err(err)
-        }.boxed()
+        let cache = self.session.cache().cloned();
+        let cache = cache.as_ref().map(|cache| &**cache);
+
+        self.cached_session = authenticate_with_cached_session(&mut self.scrobbler, &self.config, cache);
+        if self.cached_session {
+            future::ok(()).boxed()
+        } else {
+            authenticate_with_password(&mut self.scrobbler, &self.config, cache).into_future().boxed()
+        }
     }
 
-    pub fn update_current_track(&mut self, track_id: SpotifyId, force_new_track: bool) {
-        if !force_new_track {
-            let mut new_track_detected = false;
-            match self.current_track_id {
-                None => { 
-                    new_track_detected = true;
-                },
-                Some(id) => {
-                    if id != track_id {
-                        new_track_detected = true;
-                    }
-                }
-            }
+    /// Runs `request`, signing in with the password and running it again if
+    /// Last.fm refused the cached session key, e.g. because it was revoked.
+    fn with_session<T, F>(&mut self, request: F) -> Result<T, rustfm_scrobble::ScrobblerError>
+        where F: Fn(&rustfm_scrobble::Scrobbler) -> Result<T, rustfm_scrobble::ScrobblerError>
+    {
+        let result = request(&self.scrobbler);
+        let invalid_session = match result {
+            Err(ref err) => self.cached_session && is_invalid_session(err),
+            Ok(_) => false,
+        };
+        if !invalid_session {
+            return result;
+        }
+
+        warn!("Last.fm refused the cached session, signing in again");
+        self.cached_session = false;
 
-            if !new_track_detected {
-                return
+        let cache = self.session.cache().cloned();
+        let cache = cache.as_ref().map(|cache| &**cache);
+        if let Some(cache) = cache {
+            if let Err(err) = cache.remove_lastfm_session_key(&self.config.username) {
+                warn!("Cannot remove cached Last.fm session: {}", err);
             }
         }
 
-        if self.can_scrobble_track() {
-            self.start_scrobble();
+        match authenticate_with_password(&mut self.scrobbler, &self.config, cache) {
+            Ok(()) => request(&self.scrobbler),
+            Err(err) => {
+                error!("Authentication error: {:?}", err);
+                self.config.status.set_auth(AuthState::Failed(err.to_string()));
+                Err(err)
+            }
         }
-
-        self.new_track_future = self.set_new_track(track_id);
     }
 
-    pub fn set_new_track(&mut self, track_id: SpotifyId) -> BoxFuture<(), ()> {
+
+    pub fn set_new_track(&mut self, track_id: SpotifyUri, device: PlaybackDevice,
+                         started_at: i64, excluded: bool) -> BoxFuture<(), ()> {
         self.current_track_id = Some(track_id);
+        self.current_track_started_at = started_at;
+        self.current_track_device = Some(device);
         self.current_track_start = Some(Instant::now());
         self.current_track_meta = None;
         self.current_track_scrobbled = false;
+        self.current_track_scrobble_failed = false;
+        self.current_track_excluded = excluded;
 
         future::ok(()).boxed()
     }
 
-    pub fn get_track_meta(&mut self, track_id: SpotifyId) -> BoxFuture<Scrobble, ScrobbleError> {
+    pub fn get_track_meta(&self, track: &SpotifyUri) -> BoxFuture<TrackInfo, ScrobbleError> {
+        match *track {
+            SpotifyUri::Track(track_id) => self.get_remote_track_meta(track_id),
+            // Local files never reach Spotify's servers, all we know is in the URI
+            SpotifyUri::Local { ref artist, ref album, ref title, duration } => {
+                future::ok(TrackInfo {
+                    name: title.clone(),
+                    artist: artist.clone(),
+                    artists: vec![artist.clone()],
+                    album: album.clone(),
+                    cover_url: None,
+                    artist_uri: None,
+                    album_uri: None,
+                    duration_ms: duration.map(|seconds| seconds * 1000),
+                }).boxed()
+            }
+            _ => {
+                future::err(ScrobbleError::new(format!("Cannot scrobble {}", track))).boxed()
+            }
+        }
+    }
+
+    fn get_remote_track_meta(&self, track_id: SpotifyId) -> BoxFuture<TrackInfo, ScrobbleError> {
         let session = self.session.clone();
 
         Track::get(&session, track_id).and_then(move |track| {
-            let track_name = track.clone().name;
-            let artist = *track.artists.first().expect("No artists");
-            Artist::get(&session, artist).map(move |artist| (track_name, artist.name.clone(), track, session))
-        }).and_then(move |(track_name, artist_name, track_meta, session)| {
-            Album::get(&session, track_meta.album).map(|album| (track_name, artist_name, album.name.clone()))
+            let artists = future::join_all(track.artists.iter()
+                .map(|&artist| Artist::get(&session, artist))
+                .collect::<Vec<_>>());
+            let album = Album::get(&session, track.album);
+            artists.join(album).map(move |(artists, album)| (track, artists, album))
         }).map_err(move |err| {
             ScrobbleError::new(format!("{:?}", err).to_owned())
-        }).and_then(move |(track, artist, album)| {
-            future::ok(Scrobble::new(&artist, &track, &album))
+        }).map(move |(track, artists, album)| {
+            let artist = artists.first().expect("No artists").clone();
+            TrackInfo {
+                name: track.name,
+                artist: artist.name,
+                artists: artists.into_iter().map(|artist| artist.name).collect(),
+                album: album.name,
+                cover_url: album.covers.first()
+                    .map(|cover| format!("https://i.scdn.co/image/{}", cover.to_base16())),
+                artist_uri: Some(format!("spotify:artist:{}", artist.id.to_base62())),
+                album_uri: Some(format!("spotify:album:{}", album.id.to_base62())),
+                duration_ms: if track.duration_ms > 0 { Some(track.duration_ms as u32) } else { None },
+            }
         }).boxed()
     }
 
-    pub fn send_now_playing(&self, track: &Scrobble) -> BoxFuture<(), ScrobbleError> {
-        info!("Now-playing scrobble: {:?}", track);
+    pub fn send_now_playing(&mut self, track: &Scrobble) -> BoxFuture<(), ScrobbleError> {
+        info!("Now-playing scrobble: {:?} on {}", track, self.current_device_name());
+
+        let result = self.with_session(|scrobbler| scrobbler.now_playing(track));
+        NOW_PLAYING.inc(&[("result", if result.is_ok() { "ok" } else { "failed" })]);
 
-        match self.scrobbler.now_playing(track) {
+        match result {
             Ok(_) => future::ok(()),
             Err(err) => future::err(ScrobbleError::new(format!("{:?}", err)))
         }.boxed()
     }
 
+    fn current_play(&self) -> Option<Play> {
+        self.current_track_id.clone().map(|track| Play {
+            track: track,
+            started_at: self.current_track_started_at,
+        })
+    }
+
     pub fn start_scrobble(&mut self) {
-        self.scrobble_future = match self.current_track_meta {
-            Some(ref meta) => {
-                let scrobble = &meta.clone();
-                Some(self.send_scrobble(scrobble))
+        if let Some(play) = self.current_play() {
+            self.config.scrobbled.insert(play);
+        }
+
+        let scrobble = self.current_track_meta.as_ref().map(TrackInfo::to_scrobble);
+        self.scrobble_future = match scrobble {
+            Some(scrobble) => {
+                Some(self.send_scrobble(&scrobble))
             },
             None => {
                 error!("No track meta-data available for scrobble");
                 None
             }
+        };
+        if self.scrobble_future.is_some() {
+            PENDING_SCROBBLES.add(&[], 1.0);
+            self.config.status.set_scrobble_in_flight(true);
         }
     }
 
-    pub fn send_scrobble(&self, scrobble: &Scrobble) -> BoxFuture<(), ScrobbleError> {
-        info!("Scrobbling: {:?}", scrobble);
+    pub fn send_scrobble(&mut self, scrobble: &Scrobble) -> BoxFuture<(), ScrobbleError> {
+        info!("Scrobbling: {:?} played on {}", scrobble, self.current_device_name());
+
+        let result = self.with_session(|scrobbler| scrobbler.scrobble(scrobble));
+        SCROBBLES.inc(&[("sink", "lastfm"), ("result", if result.is_ok() { "submitted" } else { "failed" })]);
 
-        match self.scrobbler.scrobble(scrobble) {
+        match result {
             Ok(_) => future::ok(()),
             Err(err) => future::err(ScrobbleError::new(format!("{:?}", err)))
         }.boxed()
     }
 
+    /// Settles the scrobble in flight, if any.
+    fn poll_scrobble(&mut self) -> Poll<(), ScrobbleError> {
+        let result = match self.scrobble_future {
+            Some(ref mut scrobble_future) => scrobble_future.poll(),
+            None => return Ok(Async::Ready(())),
+        };
+
+        match result {
+            Ok(Async::NotReady) => return result,
+            Ok(Async::Ready(())) => {
+                self.current_track_scrobbled = true;
+                self.config.status.update_track(|now_playing| now_playing.scrobbled = true);
+                LAST_SCROBBLE.set(&[], (now_ms() / 1000) as f64);
+                self.notify_webhooks(WebhookEvent::Scrobble);
+            }
+            Err(_) => self.current_track_scrobble_failed = true,
+        }
+
+        self.scrobble_future = None;
+        PENDING_SCROBBLES.add(&[], -1.0);
+        self.config.status.set_scrobble_in_flight(false);
+        result
+    }
+
+    fn outcome(&self) -> ScrobbleOutcome {
+        if self.current_track_excluded {
+            ScrobbleOutcome::Excluded
+        } else if self.current_track_scrobble_failed {
+            ScrobbleOutcome::Failed
+        } else if self.current_track_scrobbled ||
+                  self.current_play().map_or(false, |play| self.config.scrobbled.contains(&play)) {
+            ScrobbleOutcome::Scrobbled
+        } else {
+            ScrobbleOutcome::TooShort
+        }
+    }
+
+    fn record_play(&self, item: &PlaybackItem, played_ms: u64) {
+        let meta = self.current_track_meta.as_ref();
+        let entry = HistoryEntry {
+            timestamp: item.started_at / 1000,
+            track_uri: item.track.to_string(),
+            track: meta.map(|meta| meta.name.clone()),
+            artist_uri: meta.and_then(|meta| meta.artist_uri.clone()),
+            artist: meta.map(|meta| meta.artist.clone()),
+            album_uri: meta.and_then(|meta| meta.album_uri.clone()),
+            album: meta.map(|meta| meta.album.clone()),
+            duration_ms: meta.and_then(|meta| meta.duration_ms),
+            played_ms: played_ms,
+            device: item.device.name.clone(),
+            device_ident: item.device.ident.clone(),
+            context_uri: item.context_uri.clone(),
+            outcome: self.outcome(),
+        };
+
+        if let Some(ref history) = self.config.history {
+            if let Err(err) = history.append(&entry) {
+                warn!("Cannot write to {}: {}", history.path().display(), err);
+            }
+        }
+
+        for store in &self.config.stores {
+            if let Err(err) = store.record(&entry) {
+                warn!("Cannot record play in {:?}: {}", store, err);
+            }
+        }
+
+        self.config.status.track_ended(entry);
+    }
+
+    fn handle_command(&mut self, command: ScrobblerCommand) {
+        let track = match self.current_track_id {
+            Some(ref track) => track.to_string(),
+            None => {
+                info!("Nothing playing, ignoring {:?}", command);
+                return;
+            }
+        };
+
+        match command {
+            ScrobblerCommand::Skip => {
+                info!("Not scrobbling {}, as asked", track);
+                self.current_track_excluded = true;
+                self.config.status.update_track(|now_playing| now_playing.excluded = true);
+            }
+            ScrobblerCommand::ForceScrobble => {
+                if self.current_track_scrobbled || self.scrobble_future.is_some() {
+                    info!("{} is scrobbled already", track);
+                } else if self.current_track_meta.is_none() {
+                    warn!("Cannot scrobble {} before its metadata is fetched", track);
+                } else {
+                    self.current_track_excluded = false;
+                    self.config.status.update_track(|now_playing| now_playing.excluded = false);
+                    self.start_scrobble();
+                }
+            }
+        }
+    }
+
+    fn notify_webhooks(&self, event: WebhookEvent) {
+        let (track_id, meta) = match (self.current_track_id.as_ref(), self.current_track_meta.as_ref()) {
+            (Some(track_id), Some(meta)) => (track_id, meta),
+            _ => return,
+        };
+        let device = self.current_track_device.as_ref();
+
+        self.config.webhooks.notify(Notification {
+            event: event,
+            account: String::new(),
+            track: meta.name.clone(),
+            artists: meta.artists.clone(),
+            album: meta.album.clone(),
+            cover_url: meta.cover_url.clone(),
+            device: device.map(|device| device.name.clone()),
+            device_ident: device.map(|device| device.ident.clone()),
+            track_uri: track_id.to_string(),
+            timestamp: self.current_track_started_at / 1000,
+        });
+    }
+
+    fn current_device_name(&self) -> String {
+        match self.current_track_device {
+            Some(ref device) => device.to_string(),
+            None => "unknown device".to_owned(),
+        }
+    }
+
     fn can_scrobble_track(&self) -> bool {
-        if self.current_track_scrobbled {
+        if self.current_track_scrobbled || self.current_track_excluded || self.config.status.is_paused() {
+            return false
+        }
+
+        if self.current_play().map_or(false, |play| self.config.scrobbled.contains(&play)) {
             return false
         }
 
@@ -201,41 +606,36 @@ impl Future for Scrobbler {
             Ok(Async::Ready(_)) => {
                 info!("Authenticated with Last.fm");
                 self.auth_future = future::empty().boxed();
+                self.config.status.set_auth(AuthState::Authenticated);
             },
             Ok(Async::NotReady) => {
             },
             Err(err) => {
+                // A failed future mustn't be polled again
+                self.auth_future = future::empty().boxed();
                 error!("Authentication error: {:?}", err);
+                self.config.status.set_auth(AuthState::Failed(err.to_string()));
                 return Err(())
             }
         }
 
+        while let Ok(Async::Ready(Some(command))) = self.commands.poll() {
+            self.handle_command(command);
+        }
+
         if self.can_scrobble_track() {
             self.start_scrobble();
         }
 
-        let mut track_scrobbled = false;
-        match self.scrobble_future {
-            Some(ref mut scrobble_future) => {
-                match scrobble_future.poll() {
-                    Ok(Async::Ready(_)) => {
-                        track_scrobbled = true;
-                    },
-                    Ok(Async::NotReady) => {
-                        return Ok(Async::NotReady)
-                    },
-                    Err(err) => {
-                        error!("Scrobbling error: {:?}", err);
-                        return Err(())
-                    }
-                }
+        match self.poll_scrobble() {
+            Ok(Async::Ready(())) => (),
+            Ok(Async::NotReady) => {
+                return Ok(Async::NotReady)
             },
-            None => ()
-        }
-
-        if track_scrobbled {
-            self.scrobble_future = None;
-            self.current_track_scrobbled = true;
+            Err(err) => {
+                error!("Scrobbling error: {:?}", err);
+                return Err(())
+            }
         }
 
         match self.new_track_future.poll() {
@@ -243,9 +643,10 @@ impl Future for Scrobbler {
                 self.new_track_future = future::empty().boxed();
                 self.current_track_scrobbled = false;
 
-                match self.current_track_id {
+                match self.current_track_id.clone() {
+                    Some(_) if self.current_track_excluded => (),
                     Some(track_id) => {
-                        self.meta_fetch_future = self.get_track_meta(track_id);
+                        self.meta_fetch_future = self.get_track_meta(&track_id);
                     },
                     None => {
 
@@ -256,6 +657,7 @@ impl Future for Scrobbler {
 
             },
             Err(err) => {
+                self.new_track_future = future::empty().boxed();
                 error!("Failed to set new current track: {:?}", err);
                 return Err(())
             }
@@ -264,13 +666,16 @@ impl Future for Scrobbler {
         match self.meta_fetch_future.poll() {
             Ok(Async::Ready(ref track)) => {
                 self.meta_fetch_future = future::empty().boxed();
-                self.now_playing_future = self.send_now_playing(track);
+                self.now_playing_future = self.send_now_playing(&track.to_scrobble());
                 self.current_track_meta = Some(track.clone());
+                self.config.status.update_track(|now_playing| now_playing.info = Some(track.clone()));
+                self.notify_webhooks(WebhookEvent::NowPlaying);
             },
             Ok(Async::NotReady) => {
                 
             },
             Err(err) => {
+                self.meta_fetch_future = future::empty().boxed();
                 error!("Metadata fetch error: {:?}", err);
                 return Err(())
             }
@@ -284,6 +689,7 @@ impl Future for Scrobbler {
                 
             },
             Err(err) => {
+                self.now_playing_future = future::empty().boxed();
                 error!("Now Playing error: {:?}", err);
                 return Err(())
             }
@@ -293,3 +699,142 @@ impl Future for Scrobbler {
     }
 
 }
+
+impl Drop for Scrobbler {
+    fn drop(&mut self) {
+        if self.scrobble_future.is_some() {
+            PENDING_SCROBBLES.add(&[], -1.0);
+        }
+    }
+}
+
+impl PlaybackObserver for Scrobbler {
+    fn handle_event(&mut self, event: &PlaybackEvent) {
+        match *event {
+            PlaybackEvent::TrackStarted(ref item) => {
+                let excluded = is_excluded(&self.config, item);
+                if excluded {
+                    info!("Not scrobbling {} ({}) on {}", item.track, item.content, item.device);
+                }
+
+                self.new_track_future = self.set_new_track(item.track.clone(), item.device.clone(),
+                                                           item.started_at, excluded);
+                self.config.status.track_started(item, excluded);
+            }
+            PlaybackEvent::TrackPaused { position_ms, .. } => self.config.status.playback(position_ms, false),
+            PlaybackEvent::Resumed { position_ms, .. } => self.config.status.playback(position_ms, true),
+            PlaybackEvent::Seeked { position_ms, .. } => {
+                let playing = self.config.status.now_playing().map_or(true, |now_playing| now_playing.playing);
+                self.config.status.playback(position_ms, playing);
+            }
+            PlaybackEvent::TrackEnded { ref item, played_ms } => {
+                if self.can_scrobble_track() {
+                    self.start_scrobble();
+                }
+
+                if let Err(err) = self.poll_scrobble() {
+                    error!("Scrobbling error: {:?}", err);
+                }
+
+                // Left unscrobbled while scrobbling was paused
+                if self.config.status.is_paused() && !self.current_track_scrobbled {
+                    self.current_track_excluded = true;
+                }
+                self.record_play(item, played_ms);
+
+                // Too short to scrobble, and it won't get any longer
+                self.current_track_start = None;
+            }
+            _ => (),
+        }
+    }
+
+    /// Failed requests have been logged and given up on by the time they
+    /// are returned, leaving the scrobbler ready for the next track, so they
+    /// don't fail the observer. It is polled again to make progress on the
+    /// requests the failure returned early from.
+    fn poll(&mut self) -> Poll<(), ()> {
+        match Future::poll(self) {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(_)) | Err(()) => Ok(Async::Ready(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::config::DeviceType;
+
+    fn config(excluded_content: &[ContentType]) -> ScrobblerConfig {
+        ScrobblerConfig {
+            api_key: String::new(),
+            api_secret: String::new(),
+            username: String::new(),
+            password: String::new(),
+            excluded_content: excluded_content.to_vec(),
+            included_devices: Vec::new(),
+            excluded_devices: Vec::new(),
+            scrobbled: ScrobbledPlays::default(),
+            history: None,
+            stores: Vec::new(),
+            status: ScrobblerStatus::new(),
+            webhooks: Webhooks::none(),
+        }
+    }
+
+    fn item(uri: &str, content: ContentType) -> PlaybackItem {
+        PlaybackItem {
+            track: SpotifyUri::parse(uri).unwrap(),
+            content: content,
+            device: PlaybackDevice {
+                ident: "0123456789abcdef".to_owned(),
+                name: "Kitchen".to_owned(),
+                device_type: DeviceType::Speaker,
+            },
+            context_uri: None,
+            started_at: 1500000000000,
+        }
+    }
+
+    const TRACK: &'static str = "spotify:track:4uLU6hMCjMI75M1A2tKUQC";
+    const EPISODE: &'static str = "spotify:episode:4uLU6hMCjMI75M1A2tKUQC";
+
+    #[test]
+    fn scrobbles_music() {
+        assert!(!is_excluded(&config(DEFAULT_EXCLUDED_CONTENT), &item(TRACK, ContentType::Music)));
+        assert!(!is_excluded(&config(DEFAULT_EXCLUDED_CONTENT),
+                             &item("spotify:local:Artist:Album:Title:180", ContentType::Music)));
+    }
+
+    // Excluded items never have their metadata fetched, which an episode
+    // doesn't have
+    #[test]
+    fn episodes_are_never_fetched() {
+        assert!(is_excluded(&config(DEFAULT_EXCLUDED_CONTENT), &item(EPISODE, ContentType::Episode)));
+        assert!(is_excluded(&config(&[]), &item(EPISODE, ContentType::Episode)));
+        // Even when the context made it look like music
+        assert!(is_excluded(&config(&[]), &item(EPISODE, ContentType::Music)));
+    }
+
+    #[test]
+    fn excludes_configured_content() {
+        assert!(is_excluded(&config(DEFAULT_EXCLUDED_CONTENT), &item(TRACK, ContentType::Audiobook)));
+        assert!(!is_excluded(&config(&[]), &item(TRACK, ContentType::Audiobook)));
+        assert!(is_excluded(&config(&[]), &item(TRACK, ContentType::Ad)));
+    }
+
+    #[test]
+    fn excludes_devices() {
+        let mut config = config(&[]);
+        config.excluded_devices = vec!["kitchen".to_owned()];
+        assert!(is_excluded(&config, &item(TRACK, ContentType::Music)));
+
+        config.excluded_devices.clear();
+        config.included_devices = vec!["Bedroom".to_owned()];
+        assert!(is_excluded(&config, &item(TRACK, ContentType::Music)));
+
+        config.included_devices.push("0123456789abcdef".to_owned());
+        assert!(!is_excluded(&config, &item(TRACK, ContentType::Music)));
+    }
+}
@@ -3,12 +3,17 @@ use futures::stream::BoxStream;
 use futures::sync::mpsc;
 use futures::{Future, Stream, Sink, Async, Poll};
 use protobuf::{self, Message};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use core::config::ConnectConfig;
+use core::config::{CommandPolicy, ConnectConfig};
 use core::mercury::MercuryError;
-use scrobbler::{Scrobbler, ScrobblerConfig};
+use core::metrics::{Kind, Metric};
+use playback_events::{EventTracker, PlaybackEvent, PlaybackObserver};
+use spirc_state::{PlaybackDevice, PlaybackState, StateError};
 use core::session::Session;
-use core::util::{now_ms, SpotifyId, SeqGenerator};
+use core::util::{now_ms, SeqGenerator};
 use core::version;
 
 use protocol;
@@ -26,18 +31,157 @@ pub struct SpircTask {
     sender: BoxSink<Frame, MercuryError>,
     commands: mpsc::UnboundedReceiver<SpircCommand>,
 
+    supports_rename: bool,
+    command_policy: CommandPolicy,
+    /// The last device seen playing, for handing commands sent to us back
+    /// to it.
+    last_active: Option<PlaybackDevice>,
+    devices: DeviceRegistry,
+    frames: FrameClock,
+    events: EventTracker,
+    subscribers: Vec<mpsc::UnboundedSender<PlaybackEvent>>,
+
     shutdown: bool,
     session: Session,
 
-    scrobbler: Scrobbler
+    /// Handed every event before the subscribers, and polled by this task
+    /// rather than reading `Spirc::events` from a task of its own.
+    observer: Box<PlaybackObserver>,
+}
+
+const FRAMES: Metric = Metric {
+    name: "spirc_frames_total",
+    help: "SPIRC frames received from the account's devices, by message type.",
+    kind: Kind::Counter,
+};
+
+// Devices that don't say Goodbye, e.g. when they lose their connection, are
+// forgotten once they haven't been heard from for this long.
+const DEVICE_STALE_AFTER_SECS: u64 = 60 * 60;
+
+/// Another Connect device on the account, as last heard from.
+#[derive(Debug, Clone)]
+pub struct ConnectDevice {
+    pub device: PlaybackDevice,
+    pub volume: u32,
+    pub is_active: bool,
+    pub last_seen: Instant,
+    /// The state the device last sent, which only describes what is playing
+    /// while the device is active.
+    pub state: State,
+}
+
+impl ConnectDevice {
+    fn from_frame(frame: &Frame) -> ConnectDevice {
+        let device_state = frame.get_device_state();
+
+        ConnectDevice {
+            device: PlaybackDevice::from_frame(frame),
+            volume: device_state.get_volume(),
+            is_active: device_state.get_is_active(),
+            last_seen: Instant::now(),
+            state: frame.get_state().clone(),
+        }
+    }
+
+    pub fn playback_state(&self) -> Result<PlaybackState, StateError> {
+        PlaybackState::from_state(&self.state)
+    }
+
+    fn is_stale(&self) -> bool {
+        self.last_seen.elapsed() > Duration::from_secs(DEVICE_STALE_AFTER_SECS)
+    }
+}
+
+/// The other Connect devices on the account, kept up to date from the
+/// Hello, Notify and Goodbye frames they send.
+///
+/// Clones share the same list, so it can be queried from outside the
+/// `SpircTask` while the task keeps it current.
+#[derive(Clone, Default)]
+pub struct DeviceRegistry(Arc<Mutex<HashMap<String, ConnectDevice>>>);
+
+impl DeviceRegistry {
+    /// All devices heard from recently, by name.
+    pub fn devices(&self) -> Vec<ConnectDevice> {
+        let mut devices: Vec<ConnectDevice> = self.0.lock().unwrap()
+            .values()
+            .filter(|known| !known.is_stale())
+            .cloned()
+            .collect();
+        devices.sort_by(|a, b| a.device.name.cmp(&b.device.name));
+        devices
+    }
+
+    pub fn get(&self, ident: &str) -> Option<ConnectDevice> {
+        match self.0.lock().unwrap().get(ident) {
+            Some(known) if !known.is_stale() => Some(known.clone()),
+            _ => None,
+        }
+    }
+
+    /// The device currently playing, if any.
+    pub fn active(&self) -> Option<ConnectDevice> {
+        self.0.lock().unwrap()
+            .values()
+            .find(|known| known.is_active && !known.is_stale())
+            .cloned()
+    }
+
+    /// Records the device a frame was sent by, returning the state it sent
+    /// before.
+    fn update(&self, frame: &Frame) -> Option<State> {
+        let known = ConnectDevice::from_frame(frame);
+        let mut devices = self.0.lock().unwrap();
+
+        devices.retain(|_, known| !known.is_stale());
+
+        // Only one device plays at a time, so the one that did before has
+        // stopped even if we missed its Notify
+        if known.is_active {
+            for other in devices.values_mut() {
+                other.is_active = false;
+            }
+        }
+
+        devices.insert(known.device.ident.clone(), known)
+            .map(|previous| previous.state)
+    }
+
+    fn remove(&self, ident: &str) {
+        self.0.lock().unwrap().remove(ident);
+    }
+
+    fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// When the `SpircTask` last received a frame, ours included, which tells
+/// whether its Mercury subscription still works.
+#[derive(Clone, Default)]
+pub struct FrameClock(Arc<Mutex<Option<Instant>>>);
+
+impl FrameClock {
+    pub fn last_frame(&self) -> Option<Instant> {
+        *self.0.lock().unwrap()
+    }
+
+    fn tick(&self) {
+        *self.0.lock().unwrap() = Some(Instant::now());
+    }
 }
 
 pub enum SpircCommand {
-    Shutdown
+    Shutdown,
+    Subscribe(mpsc::UnboundedSender<PlaybackEvent>),
+    Probe,
 }
 
 pub struct Spirc {
     commands: mpsc::UnboundedSender<SpircCommand>,
+    devices: DeviceRegistry,
+    frames: FrameClock,
 }
 
 fn initial_state() -> State {
@@ -54,13 +198,21 @@ fn initial_device_state(config: ConnectConfig, volume: u16) -> DeviceState {
     protobuf_init!(DeviceState::new(), {
         sw_version: version::version_string(),
         is_active: false,
-        can_play: true,
+        can_play: config.can_be_player,
         volume: volume as u32,
         name: config.name,
         capabilities => [
             @{
                 typ: protocol::spirc::CapabilityType::kCanBePlayer,
-                intValue => [1]
+                intValue => [config.can_be_player as i64]
+            },
+            @{
+                typ: protocol::spirc::CapabilityType::kHidden,
+                intValue => [config.hidden as i64]
+            },
+            @{
+                typ: protocol::spirc::CapabilityType::kSupportsRename,
+                intValue => [config.supports_rename as i64]
             },
             @{
                 typ: protocol::spirc::CapabilityType::kDeviceType,
@@ -109,8 +261,8 @@ fn initial_device_state(config: ConnectConfig, volume: u16) -> DeviceState {
 }
 
 impl Spirc {
-    pub fn new(config: ConnectConfig, session: Session, scrobbler_config: ScrobblerConfig)
-        -> (Spirc, SpircTask)
+    pub fn new<O>(config: ConnectConfig, session: Session, observer: O) -> (Spirc, SpircTask)
+        where O: PlaybackObserver + 'static
     {
         debug!("new Spirc[{}]", session.session_id());
 
@@ -132,9 +284,12 @@ impl Spirc {
         let (cmd_tx, cmd_rx) = mpsc::unbounded();
 
         let volume = 0xFFFF;
+        let supports_rename = config.supports_rename;
+        let command_policy = config.command_policy;
         let device = initial_device_state(config, volume);
 
-        let scrobbler = Scrobbler::new(scrobbler_config, session.clone());
+        let devices = DeviceRegistry::default();
+        let frames = FrameClock::default();
 
         let mut task = SpircTask {
             sequence: SeqGenerator::new(1),
@@ -148,17 +303,30 @@ impl Spirc {
             sender: sender,
             commands: cmd_rx,
 
+            supports_rename: supports_rename,
+            command_policy: command_policy,
+            last_active: None,
+            devices: devices.clone(),
+            frames: frames.clone(),
+            events: EventTracker::new(),
+            subscribers: Vec::new(),
+
             shutdown: false,
             session: session.clone(),
 
-            scrobbler: scrobbler
+            observer: Box::new(observer),
         };
 
         let spirc = Spirc {
             commands: cmd_tx,
+            devices: devices,
+            frames: frames,
         };
 
+        // Devices answer both with a Notify, so whatever is already playing
+        // is picked up straight away
         task.hello();
+        task.probe();
 
         (spirc, task)
     }
@@ -166,6 +334,28 @@ impl Spirc {
     pub fn shutdown(&self) {
         let _ = mpsc::UnboundedSender::send(&self.commands, SpircCommand::Shutdown);
     }
+
+    /// The playback events seen from now on, ending when the task does.
+    pub fn events(&self) -> mpsc::UnboundedReceiver<PlaybackEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        let _ = mpsc::UnboundedSender::send(&self.commands, SpircCommand::Subscribe(tx));
+        rx
+    }
+
+    /// The other Connect devices on the account.
+    pub fn devices(&self) -> &DeviceRegistry {
+        &self.devices
+    }
+
+    pub fn frames(&self) -> &FrameClock {
+        &self.frames
+    }
+
+    /// Asks every device for its state. Our own probe comes back to us as
+    /// well, so this also checks the subscription while nothing plays.
+    pub fn probe(&self) {
+        let _ = mpsc::UnboundedSender::send(&self.commands, SpircCommand::Probe);
+    }
 }
 
 impl Future for SpircTask {
@@ -177,13 +367,16 @@ impl Future for SpircTask {
             let mut progress = false;
 
             if !self.shutdown {
-                match self.subscription.poll().unwrap() {
-                    Async::Ready(Some(frame)) => {
+                match self.subscription.poll() {
+                    Ok(Async::Ready(Some(frame))) => {
                         progress = true;
                         self.handle_frame(frame);
                     }
-                    Async::Ready(None) => panic!("subscription terminated"),
-                    Async::NotReady => (),
+                    Ok(Async::Ready(None)) | Err(_) => {
+                        error!("Spirc[{}] subscription terminated", self.session.session_id());
+                        return Err(());
+                    }
+                    Ok(Async::NotReady) => (),
                 }
 
                 match self.commands.poll().unwrap() {
@@ -195,20 +388,30 @@ impl Future for SpircTask {
                     Async::NotReady => (),
                 }
 
-                match self.scrobbler.poll() {
-                    Ok(Async::Ready(_)) => {
+                match self.observer.poll() {
+                    Ok(Async::Ready(())) => {
                         progress = true;
                     },
                     Ok(Async::NotReady) => {
 
                     },
-                    Err(err) => {
-                        error!("Scrobbler error: {:?}", err);
+                    Err(()) => {
+                        // A failed observer is not polled again, the
+                        // events are still published to subscribers
+                        error!("Spirc[{}] playback observer failed, dropping it", self.session.session_id());
+                        self.observer = Box::new(());
                     }
                 }
             }
 
-            let poll_sender = self.sender.poll_complete().unwrap();
+            let poll_sender = match self.sender.poll_complete() {
+                Ok(poll_sender) => poll_sender,
+                Err(_) if self.shutdown => Async::Ready(()),
+                Err(_) => {
+                    error!("Spirc[{}] could not send frame", self.session.session_id());
+                    return Err(());
+                }
+            };
 
             // Only shutdown once we've flushed out all our messages
             if self.shutdown && poll_sender.is_ready() {
@@ -227,9 +430,14 @@ impl SpircTask {
         match cmd {
             SpircCommand::Shutdown => {
                 CommandSender::new(self, MessageType::kMessageTypeGoodbye).send();
+                self.devices.clear();
                 self.shutdown = true;
                 self.commands.close();
             }
+            SpircCommand::Subscribe(subscriber) => {
+                self.subscribers.push(subscriber);
+            }
+            SpircCommand::Probe => self.probe(),
         }
     }
 
@@ -241,6 +449,11 @@ impl SpircTask {
                frame.get_seq_nr(),
                frame.get_state_update_id());
 
+        self.frames.tick();
+
+        let typ = format!("{:?}", frame.get_typ());
+        FRAMES.inc(&[("type", typ.trim_left_matches("kMessageType"))]);
+
         if frame.get_ident() == self.ident ||
            (frame.get_recipient().len() > 0 && !frame.get_recipient().contains(&self.ident)) {
             return;
@@ -248,37 +461,159 @@ impl SpircTask {
 
         match frame.get_typ() {
             MessageType::kMessageTypeHello => {
+                self.devices.update(&frame);
                 self.notify(Some(frame.get_ident()));
             }
 
+            MessageType::kMessageTypeProbe => {
+                self.notify(Some(frame.get_ident()));
+            }
+
+            MessageType::kMessageTypeGoodbye => {
+                debug!("{:?} left", frame.get_device_state().get_name());
+                self.devices.remove(frame.get_ident());
+
+                if self.events.is_following(frame.get_ident()) {
+                    let events = self.events.stop();
+                    self.publish(events);
+                }
+
+                let left = match self.last_active {
+                    Some(ref device) => device.ident == frame.get_ident(),
+                    None => false,
+                };
+                if left {
+                    self.last_active = None;
+                }
+            }
+
             MessageType::kMessageTypeNotify => {
+                let previous_state = self.devices.update(&frame);
+
                 // Inactive devices won't be playing anything, so we don't need to scrobble it
                 if !frame.get_device_state().get_is_active() {
+                    if self.events.is_following(frame.get_ident()) {
+                        let events = self.events.stop();
+                        self.publish(events);
+                    }
                     return ();
                 }
 
-                //info!("{:?}", frame);
-                let state = frame.get_state();
-                let playing_index = state.get_playing_track_index();
-                let tracks = state.get_track();
-                if tracks.len() > 0 {
-                    let playing_track_ref = state.get_track()[playing_index as usize].clone();
-                    let playing_track_spotify_id = SpotifyId::from_raw(playing_track_ref.get_gid());
-                    let force_new_track = state.get_position_ms() == 0;
-
-                    self.scrobbler.update_current_track(playing_track_spotify_id, force_new_track);
-                    info!("Relevant SPIRC frame; Current track Spotify ID: {:?}", playing_track_spotify_id);
+                // Devices repeat their state in answer to every Hello and Probe
+                if previous_state.as_ref() == Some(frame.get_state()) {
+                    return;
+                }
+
+                let device = PlaybackDevice::from_frame(&frame);
+                self.last_active = Some(device.clone());
+
+                // Playback has moved on since we last refused a command
+                if self.device.has_error_message() {
+                    self.device.clear_error_code();
+                    self.device.clear_error_message();
+                }
+
+                let state = match PlaybackState::from_state(frame.get_state()) {
+                    Ok(state) => state,
+                    Err(StateError::EmptyQueue) => return,
+                    Err(err) => {
+                        warn!("Ignoring state from {}: {}", device, err);
+                        return;
+                    }
+                };
+
+                if let Some(ref ad) = state.ad {
+                    info!("Advertisement playing: {:?} ({})", ad.advertiser, ad.product);
+                }
+
+                if let Some(ref track) = state.track {
+                    info!("Relevant SPIRC frame; Current track: {} ({}) on {}", track, state.content, device);
+                    debug!("Playing from context {:?} (fallback: {})",
+                           state.context_uri, state.playing_from_fallback);
                 }
-                
+
+                let events = self.events.update(&device, &state);
+                self.publish(events);
+            }
+
+            MessageType::kMessageTypeLoad |
+            MessageType::kMessageTypePlay |
+            MessageType::kMessageTypePlayPause |
+            MessageType::kMessageTypePause |
+            MessageType::kMessageTypeSeek |
+            MessageType::kMessageTypePrev |
+            MessageType::kMessageTypeNext |
+            MessageType::kMessageTypeVolume |
+            MessageType::kMessageTypeReplace => {
+                self.handle_playback_command(&frame);
+            }
+
+            MessageType::kMessageTypeRename => {
+                if !self.supports_rename {
+                    warn!("Ignoring rename from {:?}, renaming is disabled",
+                          frame.get_device_state().get_name());
+                    return;
+                }
+
+                info!("Renamed to {:?} by {:?}", frame.get_new_name(),
+                      frame.get_device_state().get_name());
+                self.device.set_name(frame.get_new_name().to_owned());
+                self.notify(None);
             }
             _ => (),
         }
     }
 
+    /// We can't play anything, so either hand the command on to the device
+    /// that was playing before or tell the sender why nothing happens.
+    fn handle_playback_command(&mut self, frame: &Frame) {
+        let sender = frame.get_device_state().get_name().to_owned();
+
+        if self.command_policy == CommandPolicy::HandOff {
+            if let Some(device) = self.last_active.clone() {
+                // The previous device gets the command as it was sent to us,
+                // so a Load plays what the user picked there and a Pause or
+                // Seek acts on what it is already playing
+                info!("Handing {:?} from {:?} off to {}", frame.get_typ(), sender, device);
+                let mut cs = CommandSender::new(self, frame.get_typ())
+                    .recipient(&device.ident)
+                    .position(frame.get_position())
+                    .volume(frame.get_volume());
+                if frame.has_state() {
+                    cs = cs.state(frame.get_state().clone());
+                }
+                cs.send();
+                return;
+            }
+        }
+
+        warn!("Rejecting {:?} from {:?}, this device can't play audio", frame.get_typ(), sender);
+        self.device.set_error_code(1);
+        self.device.set_error_message(format!("{} only scrobbles, it can't play audio",
+                                              self.device.get_name()));
+        self.notify(Some(frame.get_ident()));
+    }
+
+    fn publish(&mut self, events: Vec<PlaybackEvent>) {
+        for event in events {
+            debug!("{:?}", event);
+            self.observer.handle_event(&event);
+
+            // Subscribers that went away are dropped
+            self.subscribers.retain(|subscriber| {
+                mpsc::UnboundedSender::send(subscriber, event.clone()).is_ok()
+            });
+        }
+    }
+
     fn hello(&mut self) {
         CommandSender::new(self, MessageType::kMessageTypeHello).send();
     }
 
+    fn probe(&mut self) {
+        CommandSender::new(self, MessageType::kMessageTypeProbe).send();
+    }
+
     fn notify(&mut self, recipient: Option<&str>) {
         let mut cs = CommandSender::new(self, MessageType::kMessageTypeNotify);
         if let Some(s) = recipient {
@@ -323,12 +658,21 @@ impl<'a> CommandSender<'a> {
         self
     }
 
-    #[allow(dead_code)]
     fn state(mut self, state: protocol::spirc::State) -> CommandSender<'a> {
         self.frame.set_state(state);
         self
     }
 
+    fn position(mut self, position: u32) -> CommandSender<'a> {
+        self.frame.set_position(position);
+        self
+    }
+
+    fn volume(mut self, volume: u32) -> CommandSender<'a> {
+        self.frame.set_volume(volume);
+        self
+    }
+
     fn send(mut self) {
         if !self.frame.has_state() && self.spirc.device.get_is_active() {
             self.frame.set_state(self.spirc.state.clone());
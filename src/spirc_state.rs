@@ -0,0 +1,338 @@
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+use core::config::DeviceType;
+use core::util::{SpotifyId, SpotifyIdError, SpotifyUri, SpotifyUriError};
+use protocol::spirc::{CapabilityType, Frame, PlayStatus, State, TrackRef};
+
+/// The parts of a SPIRC `State` we act upon, checked for consistency.
+///
+/// Frames come from other clients on the account and can't be trusted to be
+/// complete: queues may be partially sent, indices may point past the end of
+/// the queue, and ads replace the playing track altogether.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaybackState {
+    pub status: PlayStatus,
+    pub context_uri: Option<String>,
+    pub position_ms: u32,
+    pub position_measured_at: u64,
+    pub playing_from_fallback: bool,
+    pub track: Option<SpotifyUri>,
+    pub ad: Option<AdInfo>,
+    pub content: ContentType,
+}
+
+/// What kind of item is playing, as far as can be told from the SPIRC state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentType {
+    Music,
+    Ad,
+    Episode,
+    Audiobook,
+}
+
+/// The Connect device a frame was sent by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaybackDevice {
+    pub ident: String,
+    pub name: String,
+    pub device_type: DeviceType,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdInfo {
+    pub id: Option<SpotifyId>,
+    pub advertiser: String,
+    pub product: String,
+    pub duration_ms: i32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateError {
+    EmptyQueue,
+    IndexOutOfRange { index: u32, len: usize },
+    MissingTrackId,
+    InvalidTrackId(SpotifyIdError),
+    InvalidTrackUri(SpotifyUriError),
+}
+
+impl PlaybackState {
+    pub fn from_state(state: &State) -> Result<PlaybackState, StateError> {
+        let ad = if state.has_ad() {
+            let ad = state.get_ad();
+            Some(AdInfo {
+                id: SpotifyId::try_from_raw(ad.get_gid()).ok(),
+                advertiser: ad.get_advertiser().to_owned(),
+                product: ad.get_product().to_owned(),
+                duration_ms: ad.get_duration(),
+            })
+        } else {
+            None
+        };
+
+        let context_uri = if state.has_context_uri() {
+            Some(state.get_context_uri().to_owned())
+        } else {
+            None
+        };
+
+        // While an ad plays the queue still describes the music around it,
+        // which may well be empty.
+        let track = match ad {
+            Some(ref ad) => ad.id.map(SpotifyUri::Ad),
+            None => Some(try!(playing_track(state).and_then(track_uri))),
+        };
+
+        let content = content_type(track.as_ref(), context_uri.as_ref().map(|uri| &uri[..]),
+                                   ad.is_some());
+
+        Ok(PlaybackState {
+            status: state.get_status(),
+            context_uri: context_uri,
+            position_ms: state.get_position_ms(),
+            position_measured_at: state.get_position_measured_at(),
+            playing_from_fallback: state.get_playing_from_fallback(),
+            track: track,
+            ad: ad,
+            content: content,
+        })
+    }
+}
+
+impl PlaybackDevice {
+    pub fn from_frame(frame: &Frame) -> PlaybackDevice {
+        let device_state = frame.get_device_state();
+        let device_type = device_state.get_capabilities()
+            .iter()
+            .find(|capability| capability.get_typ() == CapabilityType::kDeviceType)
+            .and_then(|capability| capability.get_intValue().first())
+            .map(|&value| DeviceType::from_capability(value))
+            .unwrap_or(DeviceType::Unknown);
+
+        PlaybackDevice {
+            ident: frame.get_ident().to_owned(),
+            name: device_state.get_name().to_owned(),
+            device_type: device_type,
+        }
+    }
+
+    /// Whether a device rule from the config refers to this device, either
+    /// by its displayed name or by its ident.
+    pub fn matches(&self, rule: &str) -> bool {
+        self.name.to_lowercase() == rule.to_lowercase() || self.ident == rule
+    }
+}
+
+impl fmt::Display for PlaybackDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} ({})", self.name, self.device_type)
+    }
+}
+
+fn content_type(track: Option<&SpotifyUri>, context_uri: Option<&str>, has_ad: bool) -> ContentType {
+    let context_type = context_uri.and_then(|uri| uri.split(':').nth(1));
+
+    match (track, context_type) {
+        _ if has_ad => ContentType::Ad,
+        (Some(&SpotifyUri::Ad(_)), _) | (_, Some("ad")) => ContentType::Ad,
+        (Some(&SpotifyUri::Chapter(_)), _) | (_, Some("audiobook")) => ContentType::Audiobook,
+        (Some(&SpotifyUri::Episode(_)), _) => ContentType::Episode,
+        _ => ContentType::Music,
+    }
+}
+
+fn playing_track(state: &State) -> Result<&TrackRef, StateError> {
+    let tracks = state.get_track();
+    if tracks.is_empty() {
+        return Err(StateError::EmptyQueue);
+    }
+
+    // Older clients, and those playing from a fallback queue, only set `index`
+    let index = if state.has_playing_track_index() {
+        state.get_playing_track_index()
+    } else {
+        state.get_index()
+    };
+
+    tracks.get(index as usize).ok_or(StateError::IndexOutOfRange {
+        index: index,
+        len: tracks.len(),
+    })
+}
+
+fn track_uri(track_ref: &TrackRef) -> Result<SpotifyUri, StateError> {
+    // Local files have no GID, only a `spotify:local:...` URI
+    if track_ref.has_uri() && !track_ref.get_uri().is_empty() {
+        match track_ref.get_uri().parse::<SpotifyUri>() {
+            Ok(uri) => return Ok(uri),
+            Err(err) => {
+                if track_ref.get_gid().is_empty() {
+                    return Err(StateError::InvalidTrackUri(err));
+                }
+                warn!("Unrecognised track URI {:?}: {}", track_ref.get_uri(), err);
+            }
+        }
+    }
+
+    if track_ref.get_gid().is_empty() {
+        return Err(StateError::MissingTrackId);
+    }
+
+    SpotifyId::try_from_raw(track_ref.get_gid())
+        .map(SpotifyUri::Track)
+        .map_err(StateError::InvalidTrackId)
+}
+
+impl FromStr for ContentType {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "music" => Ok(ContentType::Music),
+            "ad" => Ok(ContentType::Ad),
+            "episode" => Ok(ContentType::Episode),
+            "audiobook" => Ok(ContentType::Audiobook),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ContentType::Music => f.write_str("music"),
+            ContentType::Ad => f.write_str("ad"),
+            ContentType::Episode => f.write_str("episode"),
+            ContentType::Audiobook => f.write_str("audiobook"),
+        }
+    }
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::EmptyQueue => f.write_str("no tracks in queue"),
+            StateError::IndexOutOfRange { index, len } => {
+                write!(f, "playing index {} out of range for queue of {} tracks", index, len)
+            }
+            StateError::MissingTrackId => f.write_str("playing track has neither GID nor URI"),
+            StateError::InvalidTrackId(ref err) => write!(f, "invalid track GID: {}", err),
+            StateError::InvalidTrackUri(ref err) => write!(f, "invalid track URI: {}", err),
+        }
+    }
+}
+
+impl error::Error for StateError {
+    fn description(&self) -> &str {
+        match *self {
+            StateError::EmptyQueue => "no tracks in queue",
+            StateError::IndexOutOfRange { .. } => "playing index out of range",
+            StateError::MissingTrackId => "playing track has neither GID nor URI",
+            StateError::InvalidTrackId(_) => "invalid track GID",
+            StateError::InvalidTrackUri(_) => "invalid track URI",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::spirc::Ad;
+
+    fn gid(n: u8) -> Vec<u8> {
+        vec![n; 16]
+    }
+
+    fn track_ref(gid: Vec<u8>, uri: &str) -> TrackRef {
+        let mut track_ref = TrackRef::new();
+        track_ref.set_gid(gid);
+        if !uri.is_empty() {
+            track_ref.set_uri(uri.to_owned());
+        }
+        track_ref
+    }
+
+    fn state(tracks: Vec<TrackRef>) -> State {
+        let mut state = State::new();
+        state.set_status(PlayStatus::kPlayStatusPlay);
+        for track in tracks {
+            state.mut_track().push(track);
+        }
+        state
+    }
+
+    #[test]
+    fn plays_indexed_track() {
+        let mut state = state(vec![track_ref(gid(1), ""), track_ref(gid(2), "")]);
+        state.set_index(0);
+        state.set_playing_track_index(1);
+
+        let playback = PlaybackState::from_state(&state).unwrap();
+        assert_eq!(playback.track, Some(SpotifyUri::Track(SpotifyId::from_raw(&gid(2)))));
+        assert_eq!(playback.content, ContentType::Music);
+        assert!(!playback.playing_from_fallback);
+    }
+
+    #[test]
+    fn rejects_out_of_range_index() {
+        let mut state = state(vec![track_ref(gid(1), "")]);
+        state.set_playing_track_index(3);
+        assert_eq!(PlaybackState::from_state(&state), Err(StateError::IndexOutOfRange { index: 3, len: 1 }));
+
+        let state = self::state(Vec::new());
+        assert_eq!(PlaybackState::from_state(&state), Err(StateError::EmptyQueue));
+    }
+
+    #[test]
+    fn rejects_missing_gid() {
+        let state = state(vec![track_ref(Vec::new(), "")]);
+        assert_eq!(PlaybackState::from_state(&state), Err(StateError::MissingTrackId));
+
+        let state = self::state(vec![track_ref(vec![1, 2, 3], "")]);
+        assert_eq!(PlaybackState::from_state(&state),
+                   Err(StateError::InvalidTrackId(SpotifyIdError::InvalidLength(3))));
+
+        // A local file has no GID, its URI is enough
+        let state = self::state(vec![track_ref(Vec::new(), "spotify:local:Artist:Album:Title:180")]);
+        let playback = PlaybackState::from_state(&state).unwrap();
+        assert!(playback.track.map_or(false, |track| track.is_local()));
+    }
+
+    #[test]
+    fn reports_ads() {
+        let mut ad = Ad::new();
+        ad.set_gid(gid(7));
+        ad.set_advertiser("Advertiser".to_owned());
+        ad.set_product("Product".to_owned());
+        ad.set_duration(30000);
+
+        // The queue around an ad may be empty
+        let mut state = state(Vec::new());
+        state.set_ad(ad);
+
+        let playback = PlaybackState::from_state(&state).unwrap();
+        assert_eq!(playback.content, ContentType::Ad);
+        assert_eq!(playback.track, Some(SpotifyUri::Ad(SpotifyId::from_raw(&gid(7)))));
+        assert_eq!(playback.ad, Some(AdInfo {
+            id: Some(SpotifyId::from_raw(&gid(7))),
+            advertiser: "Advertiser".to_owned(),
+            product: "Product".to_owned(),
+            duration_ms: 30000,
+        }));
+    }
+
+    #[test]
+    fn falls_back_to_index() {
+        // Fallback queues only set `index`
+        let mut state = state(vec![track_ref(gid(1), ""), track_ref(gid(2), ""), track_ref(gid(3), "")]);
+        state.set_playing_from_fallback(true);
+        state.set_index(2);
+
+        let playback = PlaybackState::from_state(&state).unwrap();
+        assert!(playback.playing_from_fallback);
+        assert_eq!(playback.track, Some(SpotifyUri::Track(SpotifyId::from_raw(&gid(3)))));
+
+        state.set_index(5);
+        assert_eq!(PlaybackState::from_state(&state), Err(StateError::IndexOutOfRange { index: 5, len: 3 }));
+    }
+}
@@ -0,0 +1,193 @@
+use rusqlite::{self, Connection};
+use rusqlite::types::ToSql;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use history::{HistoryEntry, PlayStore};
+
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS artists (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS albums (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS tracks (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        artist_id TEXT REFERENCES artists (id),
+        album_id TEXT REFERENCES albums (id),
+        duration_ms INTEGER
+    );
+
+    CREATE TABLE IF NOT EXISTS plays (
+        id INTEGER PRIMARY KEY,
+        started_at INTEGER NOT NULL,
+        track_uri TEXT NOT NULL,
+        track_id TEXT REFERENCES tracks (id),
+        played_ms INTEGER NOT NULL,
+        device TEXT NOT NULL,
+        device_ident TEXT NOT NULL,
+        context_uri TEXT,
+        outcome TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS plays_started_at ON plays (started_at);
+";
+
+// Plays that count as listening, unlike skipped tracks and ads
+const LISTENED: &'static str = "plays.outcome IN ('scrobbled', 'failed')";
+
+/// Listening statistics in SQLite, with the tracks, albums and artists
+/// played keyed by their base62 `SpotifyId`.
+///
+/// Local files have no `SpotifyId`, their plays are kept without a track.
+pub struct StatsStore {
+    path: PathBuf,
+    conn: Mutex<Connection>,
+}
+
+/// A line of a top-N report.
+#[derive(Debug, Clone)]
+pub struct Ranked {
+    pub name: String,
+    pub plays: i64,
+    pub played_ms: i64,
+}
+
+impl StatsStore {
+    /// Opens `stats.sqlite` in `dir`, creating it if needed.
+    pub fn open(dir: &Path) -> rusqlite::Result<StatsStore> {
+        let path = dir.join("stats.sqlite");
+        let conn = try!(Connection::open(&path));
+        try!(conn.execute_batch(SCHEMA));
+
+        Ok(StatsStore {
+            path: path,
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn insert(&self, entry: &HistoryEntry) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = try!(conn.transaction());
+
+        let artist_id = entry.artist_uri.as_ref().and_then(|uri| spotify_id(uri, "artist"));
+        if let (Some(id), Some(name)) = (artist_id.as_ref(), entry.artist.as_ref()) {
+            try!(tx.execute("INSERT OR REPLACE INTO artists (id, name) VALUES (?1, ?2)",
+                            &[id as &ToSql, name]));
+        }
+
+        let album_id = entry.album_uri.as_ref().and_then(|uri| spotify_id(uri, "album"));
+        if let (Some(id), Some(name)) = (album_id.as_ref(), entry.album.as_ref()) {
+            try!(tx.execute("INSERT OR REPLACE INTO albums (id, name) VALUES (?1, ?2)",
+                            &[id as &ToSql, name]));
+        }
+
+        let track_id = spotify_id(&entry.track_uri, "track");
+        if let (Some(id), Some(name)) = (track_id.as_ref(), entry.track.as_ref()) {
+            let duration_ms = entry.duration_ms.map(|duration| duration as i64);
+            try!(tx.execute("INSERT OR REPLACE INTO tracks (id, name, artist_id, album_id, duration_ms)
+                             VALUES (?1, ?2, ?3, ?4, ?5)",
+                            &[id as &ToSql, name, &artist_id, &album_id, &duration_ms]));
+        }
+
+        let played_ms = entry.played_ms as i64;
+        let outcome = entry.outcome.to_string();
+        try!(tx.execute("INSERT INTO plays (started_at, track_uri, track_id, played_ms, device,
+                                            device_ident, context_uri, outcome)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                        &[&entry.timestamp as &ToSql, &entry.track_uri, &track_id, &played_ms,
+                          &entry.device, &entry.device_ident, &entry.context_uri, &outcome]));
+
+        tx.commit()
+    }
+
+    /// The most played artists between `from` and `until`, in seconds since
+    /// the epoch.
+    pub fn top_artists(&self, from: i64, until: i64, limit: i64) -> rusqlite::Result<Vec<Ranked>> {
+        self.ranking(&format!(
+            "SELECT artists.name, COUNT(*), SUM(plays.played_ms) FROM plays
+             JOIN tracks ON tracks.id = plays.track_id
+             JOIN artists ON artists.id = tracks.artist_id
+             WHERE plays.started_at >= ?1 AND plays.started_at < ?2 AND {}
+             GROUP BY artists.id ORDER BY COUNT(*) DESC, artists.name LIMIT ?3", LISTENED),
+            from, until, limit)
+    }
+
+    pub fn top_albums(&self, from: i64, until: i64, limit: i64) -> rusqlite::Result<Vec<Ranked>> {
+        self.ranking(&format!(
+            "SELECT albums.name, COUNT(*), SUM(plays.played_ms) FROM plays
+             JOIN tracks ON tracks.id = plays.track_id
+             JOIN albums ON albums.id = tracks.album_id
+             WHERE plays.started_at >= ?1 AND plays.started_at < ?2 AND {}
+             GROUP BY albums.id ORDER BY COUNT(*) DESC, albums.name LIMIT ?3", LISTENED),
+            from, until, limit)
+    }
+
+    pub fn top_tracks(&self, from: i64, until: i64, limit: i64) -> rusqlite::Result<Vec<Ranked>> {
+        self.ranking(&format!(
+            "SELECT tracks.name || ' - ' || COALESCE(artists.name, '?'), COUNT(*), SUM(plays.played_ms)
+             FROM plays
+             JOIN tracks ON tracks.id = plays.track_id
+             LEFT JOIN artists ON artists.id = tracks.artist_id
+             WHERE plays.started_at >= ?1 AND plays.started_at < ?2 AND {}
+             GROUP BY tracks.id ORDER BY COUNT(*) DESC, tracks.name LIMIT ?3", LISTENED),
+            from, until, limit)
+    }
+
+    /// Every play counts here, skipped or not.
+    pub fn plays_per_device(&self, from: i64, until: i64, limit: i64) -> rusqlite::Result<Vec<Ranked>> {
+        self.ranking("SELECT device, COUNT(*), SUM(played_ms) FROM plays
+                      WHERE started_at >= ?1 AND started_at < ?2
+                      GROUP BY device_ident ORDER BY COUNT(*) DESC, device LIMIT ?3",
+                     from, until, limit)
+    }
+
+    fn ranking(&self, query: &str, from: i64, until: i64, limit: i64) -> rusqlite::Result<Vec<Ranked>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = try!(conn.prepare(query));
+
+        let rows = try!(stmt.query_map(&[&from as &ToSql, &until, &limit], |row| {
+            Ranked {
+                name: row.get(0),
+                plays: row.get(1),
+                played_ms: row.get(2),
+            }
+        }));
+
+        let ranked: rusqlite::Result<Vec<Ranked>> = rows.collect();
+        ranked
+    }
+}
+
+/// The base62 id in a Spotify URI such as `spotify:artist:<id>`.
+fn spotify_id(uri: &str, kind: &str) -> Option<String> {
+    let parts: Vec<&str> = uri.split(':').collect();
+    if parts.len() == 3 && parts[0] == "spotify" && parts[1] == kind {
+        Some(parts[2].to_owned())
+    } else {
+        None
+    }
+}
+
+impl PlayStore for StatsStore {
+    fn record(&self, entry: &HistoryEntry) -> Result<(), String> {
+        self.insert(entry).map_err(|err| err.to_string())
+    }
+}
+
+impl fmt::Debug for StatsStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StatsStore({})", self.path.display())
+    }
+}
@@ -0,0 +1,357 @@
+use futures::sync::mpsc;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use core::util::now_ms;
+use history::HistoryEntry;
+use playback_events::PlaybackItem;
+use scrobbler::TrackInfo;
+use spirc::{ConnectDevice, DeviceRegistry, FrameClock, Spirc};
+
+const RECENT_PLAYS_KEPT: usize = 50;
+
+/// How often a connected account probes its devices, see `Spirc::probe`.
+pub const PROBE_INTERVAL_SECS: u64 = 5 * 60;
+// The subscription is taken for dead after missing this many probes...
+const SUBSCRIPTION_TIMEOUT_SECS: u64 = 3 * PROBE_INTERVAL_SECS;
+// ...or when a new one hasn't seen its first frame after this long
+const SUBSCRIPTION_STARTUP_SECS: u64 = 60;
+// Longer than a reconnect takes after the longest delay between attempts
+const LIVENESS_GRACE_SECS: u64 = 10 * 60;
+
+/// Something asked of a running scrobbler from outside, e.g. through the
+/// HTTP API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrobblerCommand {
+    /// Scrobbles the current track now, however long it has played.
+    ForceScrobble,
+    /// Leaves the current track unscrobbled.
+    Skip,
+}
+
+/// Whether the scrobbler is signed in to Last.fm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthState {
+    NotConnected,
+    Pending,
+    Authenticated,
+    Failed(String),
+}
+
+/// Whether an account works end to end.
+#[derive(Debug, Clone)]
+pub struct Health {
+    /// Signed in to Spotify.
+    pub session: bool,
+    /// Receiving the frames sent by the account's devices.
+    pub subscription: bool,
+    /// Signed in to Last.fm.
+    pub lastfm: bool,
+    /// How long the account has not been ready, if it isn't.
+    pub unready_for: Option<Duration>,
+    /// How long the account has not been connected to Spotify, if it isn't.
+    pub disconnected_for: Option<Duration>,
+}
+
+impl Health {
+    /// Ready to scrobble.
+    pub fn is_ready(&self) -> bool {
+        self.is_connected() && self.lastfm
+    }
+
+    /// Signed in to Spotify and receiving frames, whatever Last.fm is up to.
+    pub fn is_connected(&self) -> bool {
+        self.session && self.subscription
+    }
+
+    /// Connected to Spotify, or not for too short a time to give up on
+    /// reconnecting. Restarting won't bring Last.fm back, so it doesn't count.
+    pub fn is_live(&self) -> bool {
+        self.disconnected_for.map_or(true, |disconnected_for| {
+            disconnected_for < Duration::from_secs(LIVENESS_GRACE_SECS)
+        })
+    }
+}
+
+/// The track the scrobbler is following.
+#[derive(Debug, Clone)]
+pub struct NowPlaying {
+    pub item: PlaybackItem,
+    /// Known once the metadata is fetched.
+    pub info: Option<TrackInfo>,
+    pub playing: bool,
+    pub scrobbled: bool,
+    pub excluded: bool,
+    position_ms: u32,
+    position_at: Instant,
+}
+
+impl NowPlaying {
+    /// How far into the track playback is, going by the last position the
+    /// device sent.
+    pub fn position_ms(&self) -> u64 {
+        let elapsed = if self.playing {
+            let elapsed = self.position_at.elapsed();
+            elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000
+        } else {
+            0
+        };
+        self.position_ms as u64 + elapsed
+    }
+}
+
+/// What an account's scrobbler is up to, kept current by the scrobbler and
+/// read by the HTTP API.
+///
+/// Clones share the same state, and outlive the `Scrobbler` created for
+/// each session, so the last plays are kept across reconnects.
+#[derive(Clone)]
+pub struct ScrobblerStatus(Arc<Mutex<StatusInner>>);
+
+struct StatusInner {
+    auth: AuthState,
+    now_playing: Option<NowPlaying>,
+    recent: VecDeque<HistoryEntry>,
+    scrobble_in_flight: bool,
+    paused: bool,
+    devices: Option<DeviceRegistry>,
+    frames: Option<FrameClock>,
+    connected_at: Option<Instant>,
+    unready_since: Option<Instant>,
+    disconnected_since: Option<Instant>,
+    commands: Option<mpsc::UnboundedSender<ScrobblerCommand>>,
+}
+
+impl ScrobblerStatus {
+    pub fn new() -> ScrobblerStatus {
+        ScrobblerStatus(Arc::new(Mutex::new(StatusInner {
+            auth: AuthState::NotConnected,
+            now_playing: None,
+            recent: VecDeque::new(),
+            scrobble_in_flight: false,
+            paused: false,
+            devices: None,
+            frames: None,
+            connected_at: None,
+            unready_since: Some(Instant::now()),
+            disconnected_since: Some(Instant::now()),
+            commands: None,
+        })))
+    }
+
+    pub fn auth(&self) -> AuthState {
+        self.0.lock().unwrap().auth.clone()
+    }
+
+    pub fn now_playing(&self) -> Option<NowPlaying> {
+        self.0.lock().unwrap().now_playing.clone()
+    }
+
+    /// The last plays, newest first, scrobbled or not.
+    pub fn recent(&self, limit: usize) -> Vec<HistoryEntry> {
+        self.0.lock().unwrap().recent.iter().rev().take(limit).cloned().collect()
+    }
+
+    /// Whether a scrobble was sent to Last.fm and not answered yet. There is
+    /// no queue, the scrobbler sends one scrobble at a time.
+    pub fn scrobble_in_flight(&self) -> bool {
+        self.0.lock().unwrap().scrobble_in_flight
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.lock().unwrap().paused
+    }
+
+    /// Stops or resumes scrobbling, plays are still recorded meanwhile.
+    pub fn set_paused(&self, paused: bool) {
+        self.0.lock().unwrap().paused = paused;
+    }
+
+    /// The account's Connect devices, none while disconnected.
+    pub fn devices(&self) -> Vec<ConnectDevice> {
+        match self.0.lock().unwrap().devices {
+            Some(ref devices) => devices.devices(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Passes `command` on to the running scrobbler, returning false if
+    /// there is none.
+    pub fn send(&self, command: ScrobblerCommand) -> bool {
+        match self.0.lock().unwrap().commands {
+            Some(ref commands) => mpsc::UnboundedSender::send(commands, command).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn health(&self) -> Health {
+        let mut inner = self.0.lock().unwrap();
+
+        let subscription = match (inner.connected_at, inner.frames.as_ref().and_then(FrameClock::last_frame)) {
+            (Some(_), Some(last_frame)) => last_frame.elapsed() < Duration::from_secs(SUBSCRIPTION_TIMEOUT_SECS),
+            (Some(connected_at), None) => connected_at.elapsed() < Duration::from_secs(SUBSCRIPTION_STARTUP_SECS),
+            (None, _) => false,
+        };
+
+        let mut health = Health {
+            session: inner.connected_at.is_some(),
+            subscription: subscription,
+            lastfm: inner.auth == AuthState::Authenticated,
+            unready_for: None,
+            disconnected_for: None,
+        };
+
+        if health.is_ready() {
+            inner.unready_since = None;
+        } else {
+            if inner.unready_since.is_none() {
+                inner.unready_since = Some(Instant::now());
+            }
+            health.unready_for = inner.unready_since.map(|since| since.elapsed());
+        }
+
+        if health.is_connected() {
+            inner.disconnected_since = None;
+        } else {
+            if inner.disconnected_since.is_none() {
+                inner.disconnected_since = Some(Instant::now());
+            }
+            health.disconnected_for = inner.disconnected_since.map(|since| since.elapsed());
+        }
+
+        health
+    }
+
+    pub fn connected(&self, spirc: &Spirc) {
+        let mut inner = self.0.lock().unwrap();
+        inner.devices = Some(spirc.devices().clone());
+        inner.frames = Some(spirc.frames().clone());
+        inner.connected_at = Some(Instant::now());
+    }
+
+    pub fn disconnected(&self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.auth = AuthState::NotConnected;
+        inner.now_playing = None;
+        inner.scrobble_in_flight = false;
+        inner.devices = None;
+        inner.frames = None;
+        inner.connected_at = None;
+        inner.commands = None;
+        if inner.unready_since.is_none() {
+            inner.unready_since = Some(Instant::now());
+        }
+        if inner.disconnected_since.is_none() {
+            inner.disconnected_since = Some(Instant::now());
+        }
+    }
+
+    /// Called by a new scrobbler, which takes commands from `commands`.
+    pub fn attach(&self, commands: mpsc::UnboundedSender<ScrobblerCommand>) {
+        let mut inner = self.0.lock().unwrap();
+        inner.auth = AuthState::Pending;
+        inner.commands = Some(commands);
+    }
+
+    pub fn set_auth(&self, auth: AuthState) {
+        self.0.lock().unwrap().auth = auth;
+    }
+
+    pub fn set_scrobble_in_flight(&self, in_flight: bool) {
+        self.0.lock().unwrap().scrobble_in_flight = in_flight;
+    }
+
+    pub fn track_started(&self, item: &PlaybackItem, excluded: bool) {
+        let position_ms = cmp::max(now_ms() - item.started_at, 0) as u32;
+
+        self.0.lock().unwrap().now_playing = Some(NowPlaying {
+            item: item.clone(),
+            info: None,
+            playing: true,
+            scrobbled: false,
+            excluded: excluded,
+            position_ms: position_ms,
+            position_at: Instant::now(),
+        });
+    }
+
+    pub fn playback(&self, position_ms: u32, playing: bool) {
+        if let Some(ref mut now_playing) = self.0.lock().unwrap().now_playing {
+            now_playing.position_ms = position_ms;
+            now_playing.position_at = Instant::now();
+            now_playing.playing = playing;
+        }
+    }
+
+    pub fn update_track<F: FnOnce(&mut NowPlaying)>(&self, f: F) {
+        if let Some(ref mut now_playing) = self.0.lock().unwrap().now_playing {
+            f(now_playing);
+        }
+    }
+
+    pub fn track_ended(&self, entry: HistoryEntry) {
+        let mut inner = self.0.lock().unwrap();
+        inner.now_playing = None;
+        if inner.recent.len() >= RECENT_PLAYS_KEPT {
+            inner.recent.pop_front();
+        }
+        inner.recent.push_back(entry);
+    }
+}
+
+impl Default for ScrobblerStatus {
+    fn default() -> ScrobblerStatus {
+        ScrobblerStatus::new()
+    }
+}
+
+impl fmt::Debug for ScrobblerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.0.lock().unwrap();
+        f.debug_struct("ScrobblerStatus")
+            .field("auth", &inner.auth)
+            .field("paused", &inner.paused)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health(lastfm: bool, disconnected_secs: Option<u64>) -> Health {
+        let disconnected_for = disconnected_secs.map(Duration::from_secs);
+        Health {
+            session: disconnected_for.is_none(),
+            subscription: disconnected_for.is_none(),
+            lastfm: lastfm,
+            unready_for: if lastfm { disconnected_for } else { Some(Duration::from_secs(3600)) },
+            disconnected_for: disconnected_for,
+        }
+    }
+
+    #[test]
+    fn liveness_ignores_lastfm() {
+        let health = health(false, None);
+        assert!(!health.is_ready());
+        assert!(health.is_live());
+    }
+
+    #[test]
+    fn liveness_allows_reconnecting() {
+        assert!(health(true, Some(60)).is_live());
+        assert!(!health(true, Some(LIVENESS_GRACE_SECS)).is_live());
+        assert!(!health(true, Some(LIVENESS_GRACE_SECS)).is_ready());
+    }
+
+    #[test]
+    fn status_starts_disconnected() {
+        let health = ScrobblerStatus::new().health();
+        assert!(!health.session && !health.is_ready());
+        assert!(health.is_live());
+        assert!(health.disconnected_for.is_some());
+    }
+}
@@ -0,0 +1,475 @@
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use reqwest;
+use serde_json;
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use core::metrics::{Kind, Metric};
+
+const TIMEOUT_SECS: u64 = 10;
+// Notifications waiting for a webhook beyond this many are dropped
+const QUEUE_SIZE: usize = 100;
+// Waits before each retry of a failed delivery
+const RETRY_DELAYS_SECS: &'static [u64] = &[1, 5, 30];
+
+const DELIVERIES: Metric = Metric {
+    name: "scrobbler_webhook_deliveries_total",
+    help: "Webhook deliveries, by event and result, dropped when a webhook falls behind.",
+    kind: Kind::Counter,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookEvent {
+    /// Playback of a track which will be scrobbled started.
+    NowPlaying,
+    Scrobble,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Events the hook is called for, all of them if empty.
+    pub events: Vec<WebhookEvent>,
+    /// Signs bodies with HMAC-SHA256, sent in `X-Scrobbler-Signature`.
+    pub secret: Option<String>,
+    /// Body to send instead of the default JSON, see `Notification::render`.
+    pub template: Option<String>,
+}
+
+/// What a webhook is told about.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub event: WebhookEvent,
+    /// Filled in by `Webhooks::notify`.
+    pub account: String,
+    pub track: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub cover_url: Option<String>,
+    pub device: Option<String>,
+    pub device_ident: Option<String>,
+    pub track_uri: String,
+    /// When the track started, in seconds since the epoch.
+    pub timestamp: i64,
+}
+
+impl Notification {
+    pub fn to_json(&self) -> String {
+        json!({
+            "event": (self.event.to_string()),
+            "account": (self.account),
+            "track": (self.track),
+            "artist": (self.artists.first()),
+            "artists": (self.artists),
+            "album": (self.album),
+            "cover_url": (self.cover_url),
+            "device": (self.device),
+            "device_ident": (self.device_ident),
+            "track_uri": (self.track_uri),
+            "timestamp": (self.timestamp)
+        }).to_string()
+    }
+
+    /// Fills in the `{{event}}`, `{{account}}`, `{{track}}`, `{{artist}}`,
+    /// `{{artists}}`, `{{album}}`, `{{cover_url}}`, `{{device}}`,
+    /// `{{device_ident}}`, `{{track_uri}}` and `{{timestamp}}` placeholders of
+    /// `template`.
+    ///
+    /// Values are escaped as JSON strings, without the quotes, so a template
+    /// like `{"text": "Now playing {{track}} by {{artists}}"}` stays valid
+    /// whatever the track is called.
+    pub fn render(&self, template: &str) -> String {
+        let artists = self.artists.join(", ");
+        let timestamp = self.timestamp.to_string();
+        let event = self.event.to_string();
+        let optional = |value: &Option<String>| value.clone().unwrap_or_default();
+
+        let values = [
+            ("event", event),
+            ("account", self.account.clone()),
+            ("track", self.track.clone()),
+            ("artist", self.artists.first().cloned().unwrap_or_default()),
+            ("artists", artists),
+            ("album", self.album.clone()),
+            ("cover_url", optional(&self.cover_url)),
+            ("device", optional(&self.device)),
+            ("device_ident", optional(&self.device_ident)),
+            ("track_uri", self.track_uri.clone()),
+            ("timestamp", timestamp),
+        ];
+
+        let mut body = template.to_owned();
+        for &(name, ref value) in values.iter() {
+            let quoted = serde_json::to_string(value).unwrap();
+            body = body.replace(&format!("{{{{{}}}}}", name), &quoted[1..quoted.len() - 1]);
+        }
+        body
+    }
+}
+
+/// Sends notifications to the configured webhooks, each from a thread of its
+/// own, so slow or failing endpoints never hold up scrobbling or each other.
+///
+/// Clones share the same threads, which stop once all of them are dropped.
+#[derive(Clone)]
+pub struct Webhooks {
+    queues: Option<Arc<HookQueues>>,
+    account: String,
+}
+
+impl Webhooks {
+    /// Webhooks which don't send anything.
+    pub fn none() -> Webhooks {
+        Webhooks {
+            queues: None,
+            account: String::new(),
+        }
+    }
+
+    /// The same webhooks, notifying of the plays of `account`.
+    pub fn for_account(&self, account: &str) -> Webhooks {
+        Webhooks {
+            queues: self.queues.clone(),
+            account: account.to_owned(),
+        }
+    }
+
+    pub fn start(hooks: Vec<WebhookConfig>) -> Webhooks {
+        let mut queues = Vec::new();
+
+        for (index, hook) in hooks.into_iter().enumerate() {
+            let queue = Arc::new(HookQueue::new(&hook));
+            let worker = queue.clone();
+
+            let spawned = thread::Builder::new().name(format!("webhook-{}", index + 1)).spawn(move || {
+                let client = match reqwest::Client::builder().timeout(Duration::from_secs(TIMEOUT_SECS)).build() {
+                    Ok(client) => client,
+                    Err(err) => {
+                        error!("Cannot set up webhook {}: {}", hook.url, err);
+                        worker.close();
+                        return;
+                    }
+                };
+
+                while let Some(notification) = worker.pop() {
+                    deliver(&client, &hook, &notification);
+                }
+            });
+
+            match spawned {
+                Ok(_) => queues.push(queue),
+                Err(err) => error!("Cannot start the thread for webhook {}: {}", queue.url, err),
+            }
+        }
+
+        if queues.is_empty() {
+            return Webhooks::none();
+        }
+
+        Webhooks {
+            queues: Some(Arc::new(HookQueues(queues))),
+            account: String::new(),
+        }
+    }
+
+    pub fn notify(&self, mut notification: Notification) {
+        if let Some(ref queues) = self.queues {
+            notification.account = self.account.clone();
+            for queue in &queues.0 {
+                if queue.events.is_empty() || queue.events.contains(&notification.event) {
+                    queue.push(notification.clone());
+                }
+            }
+        }
+    }
+}
+
+/// The queues of all the webhooks, closed once the last `Webhooks` using
+/// them is dropped.
+struct HookQueues(Vec<Arc<HookQueue>>);
+
+impl Drop for HookQueues {
+    fn drop(&mut self) {
+        for queue in &self.0 {
+            queue.close();
+        }
+    }
+}
+
+/// The notifications waiting for one webhook, at most `QUEUE_SIZE`.
+///
+/// A now-playing notification still waiting when the next one for the same
+/// account comes in is dropped, as the track it announces is over.
+struct HookQueue {
+    url: String,
+    events: Vec<WebhookEvent>,
+    state: Mutex<QueueState>,
+    ready: Condvar,
+}
+
+struct QueueState {
+    notifications: VecDeque<Notification>,
+    closed: bool,
+}
+
+impl HookQueue {
+    fn new(hook: &WebhookConfig) -> HookQueue {
+        HookQueue {
+            url: hook.url.clone(),
+            events: hook.events.clone(),
+            state: Mutex::new(QueueState {
+                notifications: VecDeque::new(),
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn push(&self, notification: Notification) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            warn!("Webhook {} has stopped, dropping {} notification", self.url, notification.event);
+            DELIVERIES.inc(&[("event", &notification.event.to_string()), ("result", "dropped")]);
+            return;
+        }
+
+        if notification.event == WebhookEvent::NowPlaying {
+            let before = state.notifications.len();
+            state.notifications.retain(|queued| {
+                queued.event != WebhookEvent::NowPlaying || queued.account != notification.account
+            });
+            for _ in state.notifications.len()..before {
+                debug!("Webhook {} is behind, dropping stale now_playing notification", self.url);
+                DELIVERIES.inc(&[("event", "now_playing"), ("result", "dropped")]);
+            }
+        }
+
+        if state.notifications.len() >= QUEUE_SIZE {
+            // Make room by giving up on the oldest now playing, scrobbles
+            // are worth more
+            let oldest = state.notifications.iter()
+                .position(|queued| queued.event == WebhookEvent::NowPlaying);
+            let dropped = match oldest {
+                Some(index) => state.notifications.remove(index).unwrap().event,
+                None => notification.event,
+            };
+
+            warn!("Webhook {} is behind, dropping {} notification", self.url, dropped);
+            DELIVERIES.inc(&[("event", &dropped.to_string()), ("result", "dropped")]);
+            if oldest.is_none() {
+                return;
+            }
+        }
+
+        state.notifications.push_back(notification);
+        self.ready.notify_one();
+    }
+
+    /// The next notification, once there is one, or `None` once the queue
+    /// is closed and empty.
+    fn pop(&self) -> Option<Notification> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(notification) = state.notifications.pop_front() {
+                return Some(notification);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+fn deliver(client: &reqwest::Client, hook: &WebhookConfig, notification: &Notification) {
+    let body = match hook.template {
+        Some(ref template) => notification.render(template),
+        None => notification.to_json(),
+    };
+    let signature = hook.secret.as_ref().map(|secret| sign(secret, &body));
+    let event = notification.event.to_string();
+
+    let mut attempt = 0;
+    loop {
+        let mut request = client.post(&hook.url)
+            .header("Content-Type", "application/json")
+            .header("X-Scrobbler-Event", event.as_str());
+        if let Some(ref signature) = signature {
+            request = request.header("X-Scrobbler-Signature", format!("sha256={}", signature));
+        }
+
+        let error = match request.body(body.clone()).send() {
+            Ok(ref response) if response.status().is_success() => {
+                DELIVERIES.inc(&[("event", &event), ("result", "delivered")]);
+                return;
+            }
+            // Sending the same body again won't help
+            Ok(ref response) if response.status().is_client_error() &&
+                                response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                warn!("Webhook {} refused {} notification: HTTP {}", hook.url, event, response.status());
+                DELIVERIES.inc(&[("event", &event), ("result", "failed")]);
+                return;
+            }
+            Ok(response) => format!("HTTP {}", response.status()),
+            Err(err) => err.to_string(),
+        };
+
+        match RETRY_DELAYS_SECS.get(attempt) {
+            Some(&delay) => {
+                debug!("Webhook {} failed ({}), retrying in {} seconds", hook.url, error, delay);
+                thread::sleep(Duration::from_secs(delay));
+                attempt += 1;
+            }
+            None => {
+                warn!("Webhook {} failed for {} notification: {}", hook.url, event, error);
+                DELIVERIES.inc(&[("event", &event), ("result", "failed")]);
+                return;
+            }
+        }
+    }
+}
+
+/// The HMAC-SHA256 of `body` with `secret`, in hex.
+fn sign(secret: &str, body: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(body.as_bytes());
+    hmac.result().code().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl FromStr for WebhookEvent {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<WebhookEvent, ()> {
+        match s {
+            "now_playing" => Ok(WebhookEvent::NowPlaying),
+            "scrobble" => Ok(WebhookEvent::Scrobble),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            WebhookEvent::NowPlaying => "now_playing",
+            WebhookEvent::Scrobble => "scrobble",
+        })
+    }
+}
+
+impl fmt::Debug for Webhooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Webhooks")
+            .field("hooks", &self.queues.as_ref().map_or(0, |queues| queues.0.len()))
+            .field("account", &self.account)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue() -> HookQueue {
+        HookQueue::new(&WebhookConfig {
+            url: "http://127.0.0.1:9/hook".to_owned(),
+            events: Vec::new(),
+            secret: None,
+            template: None,
+        })
+    }
+
+    fn notification(event: WebhookEvent, account: &str, track: &str) -> Notification {
+        Notification {
+            event: event,
+            account: account.to_owned(),
+            track: track.to_owned(),
+            artists: vec!["Artist".to_owned()],
+            album: "Album".to_owned(),
+            cover_url: None,
+            device: None,
+            device_ident: None,
+            track_uri: "spotify:track:4uLU6hMCjMI75M1A2tKUQC".to_owned(),
+            timestamp: 1500000000,
+        }
+    }
+
+    fn queued(queue: &HookQueue) -> Vec<String> {
+        queue.state.lock().unwrap().notifications.iter()
+            .map(|queued| format!("{} {} {}", queued.event, queued.account, queued.track))
+            .collect()
+    }
+
+    #[test]
+    fn drops_stale_now_playing() {
+        let queue = queue();
+        queue.push(notification(WebhookEvent::NowPlaying, "alice", "one"));
+        queue.push(notification(WebhookEvent::NowPlaying, "bob", "one"));
+        queue.push(notification(WebhookEvent::Scrobble, "alice", "one"));
+        queue.push(notification(WebhookEvent::NowPlaying, "alice", "two"));
+
+        assert_eq!(queued(&queue), vec!["now_playing bob one", "scrobble alice one", "now_playing alice two"]);
+    }
+
+    #[test]
+    fn stays_bounded() {
+        let queue = queue();
+        queue.push(notification(WebhookEvent::NowPlaying, "alice", "playing"));
+        for i in 1..QUEUE_SIZE {
+            queue.push(notification(WebhookEvent::Scrobble, "alice", &i.to_string()));
+        }
+        assert_eq!(queued(&queue).len(), QUEUE_SIZE);
+
+        // The now playing makes room...
+        queue.push(notification(WebhookEvent::Scrobble, "alice", "last"));
+        let notifications = queued(&queue);
+        assert_eq!(notifications.len(), QUEUE_SIZE);
+        assert_eq!(notifications[0], "scrobble alice 1");
+        assert_eq!(notifications[QUEUE_SIZE - 1], "scrobble alice last");
+
+        // ...then newer notifications are dropped
+        queue.push(notification(WebhookEvent::Scrobble, "alice", "dropped"));
+        queue.push(notification(WebhookEvent::NowPlaying, "bob", "dropped"));
+        assert_eq!(queued(&queue), notifications);
+    }
+
+    #[test]
+    fn delivers_queue_after_close() {
+        let queue = queue();
+        queue.push(notification(WebhookEvent::Scrobble, "alice", "one"));
+        queue.close();
+        queue.push(notification(WebhookEvent::Scrobble, "alice", "two"));
+
+        assert_eq!(queue.pop().map(|notification| notification.track), Some("one".to_owned()));
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn workers_stop_with_last_handle() {
+        let queue = Arc::new(queue());
+        let webhooks = Webhooks {
+            queues: Some(Arc::new(HookQueues(vec![queue.clone()]))),
+            account: String::new(),
+        };
+
+        let alice = webhooks.for_account("alice");
+        drop(webhooks);
+        alice.notify(notification(WebhookEvent::NowPlaying, "", "one"));
+        assert_eq!(queued(&queue), vec!["now_playing alice one"]);
+
+        drop(alice);
+        assert_eq!(queue.pop().map(|notification| notification.account), Some("alice".to_owned()));
+        assert!(queue.pop().is_none());
+    }
+}